@@ -2,7 +2,14 @@ job "spam" {
   type = "service"
 
   group "spam" {
-    count = 2
+    # Unsubscribes and other state live in a local SQLite database, so only
+    # one instance may run at a time.
+    count = 1
+
+    ephemeral_disk {
+      sticky  = true
+      migrate = true
+    }
 
     network {
       port "http" { }
@@ -20,7 +27,7 @@ job "spam" {
 
       check {
         type     = "http"
-        path     = "/api/ping"
+        path     = "/api/health/ready"
         interval = "10s"
         timeout  = "2s"
       }
@@ -39,12 +46,15 @@ job "spam" {
 {{ with nomadVar "nomad/jobs/spam" }}
 APP_SECRET={{ .app_secret }}
 HIVE_SECRET={{ .hive_secret }}
+METRICS_TOKEN={{ .metrics_token }}
 AWS_ACCESS_KEY_ID={{ .aws_key_id }}
 AWS_SECRET_ACCESS_KEY={{ .aws_key_secret }}
 {{ end }}
 PORT={{ env "NOMAD_PORT_http" }}
 HIVE_URL=https://hive.datasektionen.se/api/v1
 HOST_ADDRESS=0.0.0.0
+PUBLIC_URL=https://spam.datasektionen.se
+DATABASE_PATH={{ env "NOMAD_ALLOC_DIR" }}/data/spam.db
 RUST_LOG=info
 AWS_REGION=eu-west-1
 ENV
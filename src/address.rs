@@ -0,0 +1,564 @@
+use std::fmt::{self, Display};
+
+use crate::error::Error;
+use crate::mime;
+
+/// Longest local part RFC 5321 allows.
+const MAX_LOCAL_LENGTH: usize = 64;
+/// Longest domain RFC 5321 allows.
+const MAX_DOMAIN_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// One mailbox of an address field, such as
+/// `Ture Teknolog <turetek@datasektionen.se>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mailbox {
+    /// The display name, unquoted.
+    pub name: Option<String>,
+    local: String,
+    domain: String,
+}
+
+impl Mailbox {
+    /// A mailbox for the bare address `address`, e.g. from a
+    /// `{"name": ..., "address": ...}` field.
+    pub fn new(name: Option<&str>, address: &str) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::InvalidAddress(format!("{}: {}", address, reason));
+        let mut parser = Parser::new(address).map_err(invalid)?;
+        let local = parser.next();
+        let (local, domain) = parser.addr_spec(local).map_err(invalid)?;
+        if let Some(token) = parser.next() {
+            return Err(invalid(format!(
+                "unexpected {} after {}@{}",
+                token, local, domain
+            )));
+        }
+
+        let name = match name {
+            Some(name) if name.chars().any(char::is_control) => {
+                return Err(invalid("control character in name".to_string()));
+            }
+            Some(name) => display_name(&[name]),
+            None => None,
+        };
+        Ok(Self {
+            name,
+            local,
+            domain,
+        })
+    }
+
+    /// The bare address, e.g. `turetek@datasektionen.se`.
+    pub fn address(&self) -> String {
+        if is_dot_atom(&self.local) {
+            format!("{}@{}", self.local, self.domain)
+        } else {
+            format!("{}@{}", quote(&self.local), self.domain)
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+}
+
+/// The mailbox as it goes in a header, with the name quoted or encoded as
+/// needed.
+impl Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            None => write!(f, "{}", self.address()),
+            Some(name) if !name.is_ascii() => {
+                write!(f, "{} <{}>", mime::encode_words(name), self.address())
+            }
+            Some(name) if name.split(' ').all(|word| word.chars().all(is_atext)) => {
+                write!(f, "{} <{}>", name, self.address())
+            }
+            Some(name) => write!(f, "{} <{}>", quote(name), self.address()),
+        }
+    }
+}
+
+/// Parses a mailbox list such as
+/// `"Doe, John" <john@example.com>, jane@example.com (Jane)`. Groups are
+/// flattened into their members.
+pub fn parse_list(value: &str) -> Result<Vec<Mailbox>, Error> {
+    let invalid = |reason: String| Error::InvalidAddress(format!("{}: {}", value.trim(), reason));
+    Parser::new(value)
+        .and_then(|mut parser| parser.list())
+        .map_err(invalid)
+}
+
+/// Parses a field that has to hold exactly one mailbox, like `from`.
+pub fn parse(value: &str) -> Result<Mailbox, Error> {
+    let mut mailboxes = parse_list(value)?;
+    match mailboxes.len() {
+        1 => Ok(mailboxes.remove(0)),
+        n => Err(Error::InvalidAddress(format!(
+            "{}: expected one address, found {}",
+            value.trim(),
+            n
+        ))),
+    }
+}
+
+/// The bare addresses in a mailbox list.
+pub fn addresses(value: &str) -> Result<Vec<String>, Error> {
+    Ok(parse_list(value)?.iter().map(Mailbox::address).collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A run of anything but specials and whitespace. Unlike RFC 5322
+    /// atoms, these include dots, which are checked in addresses later.
+    Atom(String),
+    Quoted(String),
+    Special(char),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Atom(atom) => write!(f, "{}", atom),
+            Token::Quoted(text) => write!(f, "{}", quote(text)),
+            Token::Special(c) => write!(f, "'{}'", c),
+        }
+    }
+}
+
+/// Splits a field into tokens, dropping whitespace and comments.
+fn tokenize(value: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            c if c.is_control() => return Err("control character".to_string()),
+            '(' => {
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some('\\') => {
+                            chars.next();
+                        }
+                        Some('(') => depth += 1,
+                        Some(')') => depth -= 1,
+                        Some(_) => {}
+                        None => return Err("unclosed comment".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    // Quoted text ends up in headers as it is, so even
+                    // escaped CR and LF are refused
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.peek().is_some_and(|c| !c.is_control()) => {
+                            text.extend(chars.next())
+                        }
+                        Some(c) if c.is_control() => {
+                            return Err("control character".to_string());
+                        }
+                        Some('\\') if chars.peek().is_some() => {
+                            return Err("control character".to_string());
+                        }
+                        Some(c) => text.push(c),
+                        None => return Err("unclosed quoted string".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(text));
+            }
+            '[' => return Err("domain literals are not supported".to_string()),
+            '<' | '>' | '@' | ',' | ':' | ';' => tokens.push(Token::Special(c)),
+            ')' | ']' | '\\' => return Err(format!("unexpected '{}'", c)),
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek()
+                    && !c.is_whitespace()
+                    && !c.is_control()
+                    && !"()<>[]:;@\\,\"".contains(c)
+                {
+                    atom.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(atom));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(value: &str) -> Result<Self, String> {
+        Ok(Self {
+            tokens: tokenize(value)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// An address list. Empty entries, as in `a@example.com,,`, are skipped.
+    fn list(&mut self) -> Result<Vec<Mailbox>, String> {
+        let mut mailboxes = Vec::new();
+        while let Some(token) = self.peek() {
+            if *token == Token::Special(',') {
+                self.pos += 1;
+                continue;
+            }
+            self.address(&mut mailboxes, false)?;
+            match self.next() {
+                None | Some(Token::Special(',')) => {}
+                Some(token) => return Err(unexpected(&token, &mailboxes)),
+            }
+        }
+        Ok(mailboxes)
+    }
+
+    /// A mailbox, or a group of them like `Styrelsen: a@example.com;`.
+    fn address(&mut self, mailboxes: &mut Vec<Mailbox>, in_group: bool) -> Result<(), String> {
+        let mut phrase = Vec::new();
+        while let Some(token @ (Token::Atom(_) | Token::Quoted(_))) = self.peek() {
+            phrase.push(token.clone());
+            self.pos += 1;
+        }
+        let words = phrase
+            .iter()
+            .map(|token| match token {
+                Token::Atom(word) | Token::Quoted(word) => word.as_str(),
+                Token::Special(_) => "",
+            })
+            .collect::<Vec<_>>();
+
+        match self.peek() {
+            Some(Token::Special('<')) => {
+                self.pos += 1;
+                let local = self.next();
+                let (local, domain) = self.addr_spec(local)?;
+                match self.next() {
+                    Some(Token::Special('>')) => {}
+                    Some(token) => {
+                        return Err(format!("unexpected {} after {}@{}", token, local, domain));
+                    }
+                    None => return Err(format!("missing '>' after {}@{}", local, domain)),
+                }
+                mailboxes.push(Mailbox {
+                    name: display_name(&words),
+                    local,
+                    domain,
+                });
+            }
+            Some(Token::Special(':')) if !in_group && !phrase.is_empty() => {
+                self.pos += 1;
+                loop {
+                    match self.peek() {
+                        Some(Token::Special(';')) => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(Token::Special(',')) => self.pos += 1,
+                        Some(_) => {
+                            self.address(mailboxes, true)?;
+                            match self.peek() {
+                                Some(Token::Special(',' | ';')) => {}
+                                Some(token) => return Err(unexpected(token, mailboxes)),
+                                None => {
+                                    return Err(format!("missing ';' after {}", words.join(" ")));
+                                }
+                            }
+                        }
+                        None => return Err(format!("missing ';' after {}", words.join(" "))),
+                    }
+                }
+            }
+            _ if phrase.len() > 1 => {
+                return Err(format!("missing <address> after {}", words.join(" ")));
+            }
+            _ => {
+                let local = match phrase.pop() {
+                    Some(local) => Some(local),
+                    None => self.next(),
+                };
+                let (local, domain) = self.addr_spec(local)?;
+                mailboxes.push(Mailbox {
+                    name: None,
+                    local,
+                    domain,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `local@domain`, starting at the already read `local`.
+    fn addr_spec(&mut self, local: Option<Token>) -> Result<(String, String), String> {
+        let local = match local {
+            Some(Token::Atom(local)) if is_dot_atom(&local) => local,
+            Some(Token::Quoted(local)) if !local.is_empty() && local.is_ascii() => local,
+            // SES doesn't support SMTPUTF8, which these need
+            Some(Token::Atom(local) | Token::Quoted(local)) if !local.is_ascii() => {
+                return Err(format!("SES can't send to non-ASCII local part {}", local));
+            }
+            Some(Token::Atom(local)) => return Err(format!("invalid local part {}", local)),
+            Some(Token::Quoted(_)) => return Err("empty local part".to_string()),
+            Some(Token::Special('@')) => return Err("empty local part".to_string()),
+            Some(token) => return Err(format!("unexpected {}", token)),
+            None => return Err("missing address".to_string()),
+        };
+        if local.len() > MAX_LOCAL_LENGTH {
+            return Err(format!(
+                "local part {} is longer than {} characters",
+                local, MAX_LOCAL_LENGTH
+            ));
+        }
+
+        match self.next() {
+            Some(Token::Special('@')) => {}
+            Some(token) => return Err(format!("unexpected {} after {}", token, local)),
+            None => return Err(format!("missing @ in {}", local)),
+        }
+
+        let domain = match self.next() {
+            Some(Token::Atom(domain)) => domain,
+            Some(token) => return Err(format!("unexpected {} after {}@", token, local)),
+            None => return Err(format!("missing domain after {}@", local)),
+        };
+        // Internationalized domains are sent as punycode
+        let domain = match domain.is_ascii() {
+            true => domain,
+            false => {
+                idna::domain_to_ascii(&domain).map_err(|_| format!("invalid domain {}", domain))?
+            }
+        };
+        if !is_domain(&domain) {
+            return Err(format!("invalid domain {}", domain));
+        }
+        Ok((local, domain))
+    }
+}
+
+fn unexpected(token: &Token, mailboxes: &[Mailbox]) -> String {
+    match mailboxes.last() {
+        Some(mailbox) => format!("unexpected {} after {}", token, mailbox.address()),
+        None => format!("unexpected {}", token),
+    }
+}
+
+/// Characters allowed in atoms (RFC 5322 section 3.2.3).
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+fn is_dot_atom(text: &str) -> bool {
+    text.split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// A host name with at least two labels, as SES can't send to anything else.
+fn is_domain(domain: &str) -> bool {
+    let labels = domain.split('.').collect::<Vec<_>>();
+    domain.len() <= MAX_DOMAIN_LENGTH
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The words of a display name with their whitespace collapsed.
+fn display_name(words: &[&str]) -> Option<String> {
+    let name = words
+        .iter()
+        .flat_map(|word| word.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(name).filter(|name| !name.is_empty())
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(value: &str) -> String {
+        match parse_list(value) {
+            Err(Error::InvalidAddress(reason)) => reason,
+            other => panic!("{} parsed as {:?}", value, other),
+        }
+    }
+
+    #[test]
+    fn mailbox_list() {
+        let addresses =
+            addresses("\"Doe, John\" <john@datasektionen.se>, jane@metaspexet.se (Jane, Doe)");
+        assert_eq!(
+            addresses.unwrap(),
+            vec!["john@datasektionen.se", "jane@metaspexet.se"]
+        );
+    }
+
+    #[test]
+    fn keeps_display_names() {
+        let mailboxes =
+            parse_list("\"Doe, John\" <john@datasektionen.se>, Ture  Teknolog <ture@kth.se>")
+                .unwrap();
+        assert_eq!(mailboxes[0].name.as_deref(), Some("Doe, John"));
+        assert_eq!(
+            mailboxes[0].to_string(),
+            "\"Doe, John\" <john@datasektionen.se>"
+        );
+        assert_eq!(mailboxes[1].to_string(), "Ture Teknolog <ture@kth.se>");
+        assert_eq!(
+            Mailbox::new(Some("åäö"), "a@datasektionen.se")
+                .unwrap()
+                .to_string(),
+            "=?UTF-8?B?w6XDpMO2?= <a@datasektionen.se>"
+        );
+        assert_eq!(
+            Mailbox::new(Some("Say \"hi\""), "a@datasektionen.se")
+                .unwrap()
+                .to_string(),
+            "\"Say \\\"hi\\\"\" <a@datasektionen.se>"
+        );
+    }
+
+    #[test]
+    fn quoted_address_is_not_used() {
+        let mailbox = parse("\"x@evil.com\" <a@datasektionen.se>").unwrap();
+        assert_eq!(mailbox.address(), "a@datasektionen.se");
+        assert_eq!(mailbox.domain(), "datasektionen.se");
+        assert_eq!(mailbox.name.as_deref(), Some("x@evil.com"));
+
+        assert_eq!(
+            error("x@evil.com <a@datasektionen.se>"),
+            "x@evil.com <a@datasektionen.se>: unexpected '<' after x@evil.com"
+        );
+        assert!(parse("x@evil.com, a@datasektionen.se").is_err());
+        assert!(Mailbox::new(None, "x@evil.com <a@datasektionen.se>").is_err());
+        assert!(Mailbox::new(Some("x"), "a@datasektionen.se, x@evil.com").is_err());
+    }
+
+    #[test]
+    fn internationalized_addresses() {
+        let mailbox = parse("Räksmörgås <ture@räksmörgås.se>").unwrap();
+        assert_eq!(mailbox.address(), "ture@xn--rksmrgs-5wao1o.se");
+        assert_eq!(
+            mailbox.to_string(),
+            "=?UTF-8?B?UsOka3Ntw7ZyZ8Olcw==?= <ture@xn--rksmrgs-5wao1o.se>"
+        );
+        assert!(parse("ture@räksmörgås..se").is_err());
+
+        let name = "Sektionens ordförande, vice ordförande och kassör för år 2026";
+        let mailbox = Mailbox::new(Some(name), "styrelsen@datasektionen.se").unwrap();
+        let header = format!("From: {}\r\n\r\n", mailbox);
+        assert!(
+            header
+                .split(' ')
+                .filter(|word| word.starts_with("=?"))
+                .all(|word| word.len() <= 75)
+        );
+        let parsed = mail_parser::MessageParser::default()
+            .parse(header.as_bytes())
+            .unwrap();
+        assert_eq!(parsed.from().unwrap().first().unwrap().name(), Some(name));
+    }
+
+    #[test]
+    fn quoted_local_parts() {
+        let mailbox = parse("\"john doe\"@datasektionen.se").unwrap();
+        assert_eq!(mailbox.address(), "\"john doe\"@datasektionen.se");
+        assert_eq!(
+            parse("\"john\"@datasektionen.se").unwrap().address(),
+            "john@datasektionen.se"
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let addresses = addresses(
+            "Styrelsen: ordf@datasektionen.se, \"Vice\" <vordf@datasektionen.se>;, d-sys@datasektionen.se",
+        );
+        assert_eq!(
+            addresses.unwrap(),
+            vec![
+                "ordf@datasektionen.se",
+                "vordf@datasektionen.se",
+                "d-sys@datasektionen.se"
+            ]
+        );
+        assert!(parse_list("undisclosed-recipients:;").unwrap().is_empty());
+        assert!(parse_list("A: B: b@datasektionen.se;;").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for (value, reason) in [
+            ("datasektionen.se", "missing @ in datasektionen.se"),
+            ("@datasektionen.se", "empty local part"),
+            ("a..b@datasektionen.se", "invalid local part a..b"),
+            (".a@datasektionen.se", "invalid local part .a"),
+            ("a@", "missing domain after a@"),
+            ("a@datasektionen", "invalid domain datasektionen"),
+            ("a@-kth.se", "invalid domain -kth.se"),
+            ("a@kth..se", "invalid domain kth..se"),
+            ("a@[127.0.0.1]", "domain literals are not supported"),
+            ("\"a@datasektionen.se", "unclosed quoted string"),
+            ("a@datasektionen.se (", "unclosed comment"),
+            (
+                "A <a@datasektionen.se",
+                "missing '>' after a@datasektionen.se",
+            ),
+            ("Ture Teknolog", "missing <address> after Ture Teknolog"),
+            ("a@b@datasektionen.se", "invalid domain b"),
+            (
+                "a@datasektionen.se\r\nBcc: x@evil.com",
+                "unexpected Bcc after a@datasektionen.se",
+            ),
+            (
+                "åäö@datasektionen.se",
+                "SES can't send to non-ASCII local part åäö",
+            ),
+        ] {
+            assert_eq!(error(value), format!("{}: {}", value.trim(), reason));
+        }
+        assert!(Mailbox::new(None, &format!("{}@datasektionen.se", "a".repeat(65))).is_err());
+        assert!(Mailbox::new(Some("a\u{0}b"), "a@datasektionen.se").is_err());
+        assert!(Mailbox::new(Some("a\r\nBcc: x@evil.com"), "a@datasektionen.se").is_err());
+    }
+
+    #[test]
+    fn rejects_control_characters_in_quoted_strings() {
+        for value in [
+            "\"a\r\nBcc: x@evil.com\"@datasektionen.se",
+            "\"a\\\r\\\nBcc: x@evil.com\"@datasektionen.se",
+            "\"a\tb\"@datasektionen.se",
+            "\"Ture\nBcc: x@evil.com\" <a@datasektionen.se>",
+            "\"a\\\u{0}\"@datasektionen.se",
+        ] {
+            assert_eq!(error(value), format!("{}: control character", value.trim()));
+        }
+        assert!(Mailbox::new(None, "\"a\r\nBcc: x@evil.com\"@datasektionen.se").is_err());
+    }
+}
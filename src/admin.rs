@@ -0,0 +1,193 @@
+use std::fmt::Debug;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::web::scope;
+use actix_web::{HttpResponse, delete, get, put, web};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use aws_sdk_sesv2::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_sesv2::primitives::DateTime;
+use aws_sdk_sesv2::types::SuppressionListReason;
+
+use crate::auth::{self, Admin};
+use crate::error::Error;
+use crate::{Client, audit, limits, queue, templates};
+
+/// Operational endpoints, for keys with the `admin` permission in Hive. The
+/// key is sent as a bearer token.
+pub fn routes() -> impl HttpServiceFactory {
+    scope("/admin")
+        .wrap(HttpAuthentication::bearer(auth::validator::<Admin>))
+        .service(list_suppressions)
+        .service(get_suppression)
+        .service(put_suppression)
+        .service(delete_suppression)
+        .service(audit::get_audit)
+        .service(limits::get_override)
+        .service(limits::put_override)
+        .service(limits::delete_override)
+        .service(templates::list_templates)
+        .service(templates::get_template)
+        .service(templates::put_template)
+        .service(templates::delete_template)
+        .service(queue::list_queue)
+}
+
+fn ses_error<E, R>(address: Option<&str>, e: SdkError<E, R>) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: Debug,
+{
+    match (e.code(), address) {
+        (Some("NotFoundException"), Some(address)) => {
+            Error::NotFound(format!("{} is not suppressed", address))
+        }
+        (Some("BadRequestException"), _) => {
+            Error::InvalidAddress(e.message().unwrap_or_default().to_string())
+        }
+        _ => Error::SesRequest(DisplayErrorContext(&e).to_string()),
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Suppression {
+    address: String,
+    /// `BOUNCE` or `COMPLAINT`.
+    reason: String,
+    updated_at: i64,
+}
+
+impl Suppression {
+    fn new(address: &str, reason: &SuppressionListReason, updated_at: &DateTime) -> Self {
+        Self {
+            address: address.to_string(),
+            reason: reason.as_str().to_string(),
+            updated_at: updated_at.secs(),
+        }
+    }
+}
+
+fn reason(value: &str) -> Result<SuppressionListReason, Error> {
+    match value.to_ascii_uppercase().as_str() {
+        "BOUNCE" => Ok(SuppressionListReason::Bounce),
+        "COMPLAINT" => Ok(SuppressionListReason::Complaint),
+        _ => Err(Error::Suppression(format!(
+            "unknown reason {}, expected BOUNCE or COMPLAINT",
+            value
+        ))),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SuppressionQuery {
+    reason: Option<String>,
+    next: Option<String>,
+    limit: Option<i32>,
+}
+
+#[derive(serde::Serialize)]
+struct SuppressionPage {
+    suppressions: Vec<Suppression>,
+    /// Pass as `next` to get the next page.
+    next: Option<String>,
+}
+
+/// Addresses on the account's suppression list in SES.
+#[get("/suppressions")]
+async fn list_suppressions(
+    ses: web::Data<Client>,
+    query: web::Query<SuppressionQuery>,
+) -> Result<HttpResponse, Error> {
+    let reasons = query.reason.as_deref().map(reason).transpose()?;
+
+    let page = ses
+        .inner
+        .list_suppressed_destinations()
+        .set_reasons(reasons.map(|reason| vec![reason]))
+        .set_next_token(query.next.clone())
+        .set_page_size(query.limit)
+        .send()
+        .await
+        .map_err(|e| ses_error(None, e))?;
+
+    Ok(HttpResponse::Ok().json(SuppressionPage {
+        suppressions: page
+            .suppressed_destination_summaries()
+            .iter()
+            .map(|s| Suppression::new(s.email_address(), s.reason(), s.last_update_time()))
+            .collect(),
+        next: page.next_token().map(str::to_string),
+    }))
+}
+
+#[get("/suppressions/{address}")]
+async fn get_suppression(
+    ses: web::Data<Client>,
+    address: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let output = ses
+        .inner
+        .get_suppressed_destination()
+        .email_address(address.as_str())
+        .send()
+        .await
+        .map_err(|e| ses_error(Some(&address), e))?;
+
+    let suppression = output
+        .suppressed_destination()
+        .map(|s| Suppression::new(s.email_address(), s.reason(), s.last_update_time()))
+        .ok_or_else(|| Error::NotFound(format!("{} is not suppressed", address)))?;
+    Ok(HttpResponse::Ok().json(suppression))
+}
+
+#[derive(serde::Deserialize)]
+struct SuppressionBody {
+    reason: String,
+}
+
+/// Suppresses an address, e.g. after a complaint that reached us some other
+/// way than SES.
+#[put("/suppressions/{address}")]
+async fn put_suppression(
+    ses: web::Data<Client>,
+    address: web::Path<String>,
+    body: web::Json<SuppressionBody>,
+) -> Result<HttpResponse, Error> {
+    ses.inner
+        .put_suppressed_destination()
+        .email_address(address.as_str())
+        .reason(reason(&body.reason)?)
+        .send()
+        .await
+        .map_err(|e| ses_error(Some(&address), e))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lets SES send to an address again.
+#[delete("/suppressions/{address}")]
+async fn delete_suppression(
+    ses: web::Data<Client>,
+    address: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    ses.inner
+        .delete_suppressed_destination()
+        .email_address(address.as_str())
+        .send()
+        .await
+        .map_err(|e| ses_error(Some(&address), e))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_reasons() {
+        assert_eq!(reason("bounce").unwrap(), SuppressionListReason::Bounce);
+        assert_eq!(
+            reason("COMPLAINT").unwrap(),
+            SuppressionListReason::Complaint
+        );
+        assert!(reason("spam").is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use actix_web::{HttpResponse, get, web};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter};
+use tracing::warn;
+
+use crate::Client;
+use crate::address;
+use crate::delivery::SendOutcome;
+use crate::error::Error;
+use crate::legacy::email::{AddressFieldLegacy, EmailRequestLegacy, ListNameLegacy};
+use crate::store::{self, Store};
+
+/// How many entries are returned per page unless asked for fewer.
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditResult {
+    Sent,
+    /// Some batches of the email were sent and some failed.
+    Partial,
+    Queued,
+    /// Nothing was sent, see [`crate::sandbox`].
+    DryRun,
+    Failed,
+}
+
+impl AuditResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditResult::Sent => "sent",
+            AuditResult::Partial => "partial",
+            AuditResult::Queued => "queued",
+            AuditResult::DryRun => "dry_run",
+            AuditResult::Failed => "failed",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sent" => Some(AuditResult::Sent),
+            "partial" => Some(AuditResult::Partial),
+            "queued" => Some(AuditResult::Queued),
+            "dry_run" => Some(AuditResult::DryRun),
+            "failed" => Some(AuditResult::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttachmentInfo {
+    pub name: String,
+    pub size: usize,
+}
+
+/// One send attempt. Nothing in it identifies the key beyond its id, and
+/// the body and recipients' addresses are left out.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub timestamp: i64,
+    pub key_id: String,
+    pub from: String,
+    pub recipients: usize,
+    pub subject: String,
+    pub template: String,
+    pub attachments: Vec<AttachmentInfo>,
+    pub result: AuditResult,
+    pub message_ids: Vec<String>,
+    /// The `Error` variant the attempt failed with.
+    pub error: Option<String>,
+}
+
+/// How many mailboxes a recipient field has. An entry may list several,
+/// and one that doesn't parse (or names a list) counts as one.
+fn count(field: &Option<ListNameLegacy>) -> usize {
+    let entries = match field {
+        Some(ListNameLegacy::List(addrs)) => addrs.iter().collect(),
+        Some(ListNameLegacy::Name(addr)) => vec![addr],
+        None => vec![],
+    };
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            AddressFieldLegacy::Address(addrs) => {
+                address::parse_list(addrs).map_or(1, |mailboxes| mailboxes.len())
+            }
+            AddressFieldLegacy::NameAndAddress(_) => 1,
+        })
+        .sum()
+}
+
+impl AuditEntry {
+    fn new(key_id: &str, from: String, recipients: usize, subject: String, template: &str) -> Self {
+        Self {
+            id: 0,
+            timestamp: store::now(),
+            key_id: key_id.to_string(),
+            from,
+            recipients,
+            subject,
+            template: template.to_string(),
+            attachments: vec![],
+            result: AuditResult::Failed,
+            message_ids: vec![],
+            error: None,
+        }
+    }
+
+    pub fn legacy(mail: &EmailRequestLegacy, key_id: &str) -> Self {
+        let from = String::try_from(&mail.from).unwrap_or_else(|_| format!("{:?}", mail.from));
+        let recipients = count(&mail.to) + count(&mail.cc) + count(&mail.bcc);
+        let mut entry = Self::new(
+            key_id,
+            from,
+            recipients,
+            mail.subject.clone(),
+            &mail.template.to_string(),
+        );
+
+        entry.attachments = mail
+            .attachments
+            .iter()
+            .flatten()
+            .map(|att| AttachmentInfo {
+                name: att.original_name.clone(),
+                size: match att.encoding.to_ascii_lowercase().as_str() {
+                    "base64" => BASE64_STANDARD
+                        .decode(&att.buffer)
+                        .map(|data| data.len())
+                        .unwrap_or(att.buffer.len()),
+                    _ => att.buffer.len(),
+                },
+            })
+            .collect();
+        entry
+    }
+
+    /// An entry for a raw message, to be filled in as the message is read.
+    pub fn raw(key_id: &str) -> Self {
+        Self::new(key_id, String::new(), 0, String::new(), "raw")
+    }
+
+    /// A send attempt turned away before its request was read, e.g. for an
+    /// invalid key.
+    pub fn rejected(key_id: &str, error: &Error) -> Self {
+        let mut entry = Self::new(key_id, String::new(), 0, String::new(), "");
+        entry.error = Some(error.name().to_string());
+        entry
+    }
+
+    /// Fills in how the attempt went.
+    pub fn outcome(mut self, result: &Result<SendOutcome, Error>) -> Self {
+        match result {
+            Ok(SendOutcome::Single(message_id)) => {
+                self.result = AuditResult::Sent;
+                self.message_ids = vec![message_id.clone()];
+            }
+            Ok(SendOutcome::Batched(summary)) => {
+                self.result = if summary.failed == 0 {
+                    AuditResult::Sent
+                } else {
+                    AuditResult::Partial
+                };
+                self.recipients = summary.sent + summary.failed;
+                self.message_ids = summary
+                    .batches
+                    .iter()
+                    .filter_map(|batch| batch.message_id.clone())
+                    .collect();
+            }
+            Ok(SendOutcome::Queued(_)) => self.result = AuditResult::Queued,
+            Ok(SendOutcome::DryRun(messages)) => {
+                self.result = AuditResult::DryRun;
+                self.recipients = messages.iter().map(|m| m.recipients.len()).sum();
+            }
+            Err(e) => {
+                self.result = AuditResult::Failed;
+                self.error = Some(e.name().to_string());
+            }
+        }
+        self
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct AuditFilter {
+    pub key_id: Option<String>,
+    pub from: Option<String>,
+    pub template: Option<String>,
+    pub result: Option<String>,
+    /// Only entries at or after this Unix timestamp.
+    pub since: Option<i64>,
+    /// Only entries before this Unix timestamp.
+    pub until: Option<i64>,
+    /// Only entries older than this id, to get the next page.
+    pub before: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+impl Store {
+    pub fn append_audit(&self, entry: &AuditEntry) -> Result<i64, Error> {
+        let attachments = serde_json::to_string(&entry.attachments)
+            .map_err(|e| Error::Store(format!("Failed to serialize attachments: {}", e)))?;
+        let message_ids = serde_json::to_string(&entry.message_ids)
+            .map_err(|e| Error::Store(format!("Failed to serialize message ids: {}", e)))?;
+
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO audit
+                (timestamp, key_id, sender, recipients, subject, template, attachments,
+                 result, message_ids, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                entry.timestamp,
+                entry.key_id,
+                entry.from,
+                entry.recipients as i64,
+                entry.subject,
+                entry.template,
+                attachments,
+                entry.result.as_str(),
+                message_ids,
+                entry.error,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Entries matching `filter`, newest first.
+    pub fn audit(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, Error> {
+        let mut conditions = vec![];
+        let mut values: Vec<Value> = vec![];
+        let mut condition = |sql: &str, value: Value| {
+            values.push(value);
+            conditions.push(format!("{} ?{}", sql, values.len()));
+        };
+
+        if let Some(key_id) = &filter.key_id {
+            condition("key_id =", key_id.clone().into());
+        }
+        if let Some(from) = &filter.from {
+            condition("sender =", from.clone().into());
+        }
+        if let Some(template) = &filter.template {
+            condition("template =", template.clone().into());
+        }
+        if let Some(result) = &filter.result {
+            condition("result =", result.clone().into());
+        }
+        if let Some(since) = filter.since {
+            condition("timestamp >=", since.into());
+        }
+        if let Some(until) = filter.until {
+            condition("timestamp <", until.into());
+        }
+        if let Some(before) = filter.before {
+            condition("id <", before.into());
+        }
+
+        let limit = filter
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let sql = format!(
+            "SELECT id, timestamp, key_id, sender, recipients, subject, template, attachments,
+                    result, message_ids, error
+             FROM audit {} ORDER BY id DESC LIMIT {}",
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            },
+            limit
+        );
+
+        let conn = self.conn();
+        let rows = conn
+            .prepare(&sql)?
+            .query_map(params_from_iter(values), |row| {
+                Ok((
+                    AuditEntry {
+                        id: row.get(0)?,
+                        timestamp: row.get(1)?,
+                        key_id: row.get(2)?,
+                        from: row.get(3)?,
+                        recipients: row.get::<_, i64>(4)? as usize,
+                        subject: row.get(5)?,
+                        template: row.get(6)?,
+                        attachments: vec![],
+                        result: AuditResult::Failed,
+                        message_ids: vec![],
+                        error: row.get(10)?,
+                    },
+                    row.get::<_, String>(7)?,
+                    row.get::<_, String>(8)?,
+                    row.get::<_, String>(9)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(mut entry, attachments, result, message_ids)| {
+                entry.attachments = serde_json::from_str(&attachments)
+                    .map_err(|e| Error::Store(format!("Invalid audit attachments: {}", e)))?;
+                entry.message_ids = serde_json::from_str(&message_ids)
+                    .map_err(|e| Error::Store(format!("Invalid audit message ids: {}", e)))?;
+                entry.result = AuditResult::parse(&result)
+                    .ok_or_else(|| Error::Store(format!("Invalid audit result: {}", result)))?;
+                Ok(entry)
+            })
+            .collect()
+    }
+}
+
+impl Client {
+    /// Records a send attempt. A failure to do so is logged but doesn't fail
+    /// the send, which has already happened.
+    pub fn audit(&self, entry: AuditEntry, result: &Result<SendOutcome, Error>) {
+        if let Err(e) = self.store.append_audit(&entry.outcome(result)) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+
+    /// Records a send attempt that was turned away by authorization.
+    pub fn audit_rejected(&self, key_id: &str, error: &Error) {
+        if let Err(e) = self
+            .store
+            .append_audit(&AuditEntry::rejected(key_id, error))
+        {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AuditPage {
+    entries: Vec<AuditEntry>,
+    /// Pass as `before` to get the next page.
+    next: Option<i64>,
+}
+
+#[get("/audit")]
+async fn get_audit(
+    ses: web::Data<Client>,
+    filter: web::Query<AuditFilter>,
+) -> Result<HttpResponse, Error> {
+    let entries = ses.store.audit(&filter)?;
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let next = entries
+        .last()
+        .filter(|_| entries.len() == limit.clamp(1, MAX_PAGE_SIZE))
+        .map(|entry| entry.id);
+    Ok(HttpResponse::Ok().json(AuditPage { entries, next }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail() -> EmailRequestLegacy {
+        serde_json::from_str(
+            r#"{
+                "key": "secret",
+                "from": "sender@datasektionen.se",
+                "to": ["a@datasektionen.se", "b@datasektionen.se"],
+                "bcc": "c@datasektionen.se, \"D, E\" <d@datasektionen.se>",
+                "subject": "Hej",
+                "content": "Hemligt",
+                "attachments[]": [
+                    { "originalname": "a.txt", "mimetype": "text/plain", "buffer": "aGVq" }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn describes_legacy_requests() {
+        let entry = AuditEntry::legacy(&mail(), "0123456789abcdef")
+            .outcome(&Ok(SendOutcome::Single("message-id".to_string())));
+        assert_eq!(entry.from, "sender@datasektionen.se");
+        assert_eq!(entry.recipients, 4);
+        assert_eq!(entry.template, "default");
+        assert_eq!(
+            entry.attachments,
+            vec![AttachmentInfo {
+                name: "a.txt".to_string(),
+                size: 3
+            }]
+        );
+        assert_eq!(entry.result, AuditResult::Sent);
+        assert_eq!(entry.message_ids, vec!["message-id"]);
+    }
+
+    #[test]
+    fn records_rejected_keys() {
+        let store = Store::in_memory().unwrap();
+        let entry = AuditEntry::rejected("0123456789abcdef", &Error::ApiKeyInvalid);
+        store.append_audit(&entry).unwrap();
+
+        let entries = store.audit(&AuditFilter::default()).unwrap();
+        assert_eq!(entries[0].key_id, "0123456789abcdef");
+        assert_eq!(entries[0].recipients, 0);
+        assert_eq!(entries[0].result, AuditResult::Failed);
+        assert_eq!(entries[0].error.as_deref(), Some("ApiKeyInvalid"));
+    }
+
+    #[test]
+    fn filters_and_pages() {
+        let store = Store::in_memory().unwrap();
+        for key_id in ["a", "b", "a", "a"] {
+            let entry = AuditEntry::legacy(&mail(), key_id).outcome(&Ok(SendOutcome::Queued(1)));
+            store.append_audit(&entry).unwrap();
+        }
+
+        let filter = AuditFilter {
+            key_id: Some("a".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = store.audit(&filter).unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), vec![4, 3]);
+        assert_eq!(page[0].result, AuditResult::Queued);
+
+        let filter = AuditFilter {
+            before: Some(3),
+            ..filter
+        };
+        let page = store.audit(&filter).unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn is_append_only() {
+        let store = Store::in_memory().unwrap();
+        let entry = AuditEntry::legacy(&mail(), "a").outcome(&Ok(SendOutcome::Queued(1)));
+        store.append_audit(&entry).unwrap();
+
+        let conn = store.conn();
+        assert!(conn.execute("UPDATE audit SET key_id = 'b'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit", []).is_err());
+    }
+}
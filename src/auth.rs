@@ -0,0 +1,161 @@
+use std::marker::PhantomData;
+
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use futures_util::future::LocalBoxFuture;
+
+use crate::Client;
+use crate::error::Error;
+use crate::hive::{self, Principal};
+use crate::{cors, oidc};
+
+/// The key id audited for requests without a key.
+const UNKNOWN_KEY: &str = "unknown";
+
+/// A Hive permission a key can be required to have.
+pub trait Permission: Clone + 'static {
+    const NAME: &'static str;
+    /// Whether browser keys are accepted instead, see
+    /// [`CorsConfig::browser_keys`](crate::cors::CorsConfig::browser_keys).
+    const BROWSER_KEYS: bool = false;
+}
+
+/// May send email, and manage its own lists.
+#[derive(Debug, Clone)]
+pub struct Sender;
+
+impl Permission for Sender {
+    const NAME: &'static str = "send";
+}
+
+/// Like [`Sender`], but browser keys are accepted too. Only for endpoints
+/// that hold browser keys to their fixed recipients.
+#[derive(Debug, Clone)]
+pub struct BrowserSender;
+
+impl Permission for BrowserSender {
+    const NAME: &'static str = "send";
+    const BROWSER_KEYS: bool = true;
+}
+
+/// May use the admin API.
+#[derive(Debug, Clone)]
+pub struct Admin;
+
+impl Permission for Admin {
+    const NAME: &'static str = "admin";
+}
+
+/// A key, a signed-in user or a browser key from the request's
+/// `Authorization: Bearer` header that has been granted `P`.
+#[derive(Debug, Clone)]
+pub struct Authorized<P: Permission> {
+    principal: Principal,
+    key_id: String,
+    permission: PhantomData<P>,
+}
+
+impl<P: Permission> Authorized<P> {
+    pub async fn check(principal: Principal) -> Result<Self, Error> {
+        match &principal {
+            Principal::Key(key) if key.is_empty() => return Err(Error::ApiKeyInvalid),
+            Principal::Browser(_, _) if !P::BROWSER_KEYS => return Err(Error::ApiKeyInvalid),
+            Principal::Browser(_, _) => {}
+            Principal::Form(_, _) => return Err(Error::ApiKeyInvalid),
+            _ => hive::require_permission(&principal, P::NAME).await?,
+        }
+        Ok(Self {
+            key_id: principal.id(),
+            principal,
+            permission: PhantomData,
+        })
+    }
+
+    /// Checks a bearer token, which is a browser key, an API key or, if
+    /// sign-in is set up, an ID token from the section's identity provider.
+    /// It also has to be allowed from `origin`.
+    async fn bearer(
+        token: String,
+        origin: Option<String>,
+        client: Option<web::Data<Client>>,
+    ) -> Result<Self, Error> {
+        let Some(client) = client else {
+            return Self::check(Principal::Key(token)).await;
+        };
+
+        let principal = match (client.config.cors.browser_key(&token), &client.oidc) {
+            (Some(browser), _) => browser,
+            (None, Some(oidc)) if oidc::is_jwt(&token) => {
+                Principal::User(oidc.verify(&token).await?)
+            }
+            _ => Principal::Key(token),
+        };
+        client
+            .config
+            .cors
+            .check_origin(&principal, origin.as_deref())?;
+        Self::check(principal).await
+    }
+
+    pub fn principal(&self) -> &Principal {
+        &self.principal
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+}
+
+/// Send endpoints authorize through this rather than [`validator`], so that
+/// the attempts it turns away are recorded in the audit log.
+impl<P: Permission> FromRequest for Authorized<P> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        // Already looked up by `validator`
+        if let Some(authorized) = request.extensions().get::<Self>().cloned() {
+            return Box::pin(async { Ok(authorized) });
+        }
+
+        let bearer = BearerAuth::from_request(request, payload).into_inner();
+        let origin = cors::origin(request.headers()).map(str::to_string);
+        let client: Option<web::Data<Client>> = request.app_data().cloned();
+        Box::pin(async move {
+            let audit = |key_id: &str, e: &Error| {
+                if let Some(client) = &client {
+                    client.audit_rejected(key_id, e);
+                }
+            };
+            let token = bearer
+                .inspect_err(|_| audit(UNKNOWN_KEY, &Error::ApiKeyInvalid))?
+                .token()
+                .to_string();
+            let key_id = hive::key_id(&token);
+            Ok(Self::bearer(token, origin, client.clone())
+                .await
+                .inspect_err(|e| audit(&key_id, e))?)
+        })
+    }
+}
+
+/// For [`HttpAuthentication::bearer`], to require `P` for a whole scope.
+/// Handlers can still extract the [`Authorized`] key without looking it up
+/// again.
+///
+/// [`HttpAuthentication::bearer`]: actix_web_httpauth::middleware::HttpAuthentication::bearer
+pub async fn validator<P: Permission>(
+    request: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let origin = cors::origin(request.headers()).map(str::to_string);
+    let client = request.app_data().cloned();
+    match Authorized::<P>::bearer(credentials.token().to_string(), origin, client).await {
+        Ok(authorized) => {
+            request.extensions_mut().insert(authorized);
+            Ok(request)
+        }
+        Err(e) => Err((e.into(), request)),
+    }
+}
@@ -0,0 +1,257 @@
+use std::env;
+
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header;
+use actix_web::web::scope;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+
+use crate::auth::{Authorized, Permission, Sender};
+use crate::error::Error;
+use crate::hive::{self, Principal};
+use crate::legacy::email::{EmailRequestLegacy, EmailTemplateTypeLegacy};
+use crate::oidc::Oidc;
+use crate::{Client, VerifiedDomains};
+
+/// Holds the signed-in user's ID token.
+const SESSION_COOKIE: &str = "spam_session";
+/// Holds the nonce of a sign-in in progress.
+const NONCE_COOKIE: &str = "spam_nonce";
+
+/// A page for composing and sending email in the browser, for officials
+/// signed in with the section's login. Needs `[oidc]` to be set up.
+pub fn routes() -> impl HttpServiceFactory {
+    scope("/compose")
+        .service(compose_page)
+        .service(login)
+        .service(callback)
+        .service(session)
+        .service(preview)
+        .service(send)
+}
+
+fn oidc(ses: &Client) -> Result<&Oidc, Error> {
+    ses.oidc
+        .as_deref()
+        .ok_or_else(|| Error::NotFound("signing in is not set up".to_string()))
+}
+
+fn public_url() -> Result<String, Error> {
+    let public_url =
+        env::var("PUBLIC_URL").map_err(|_| Error::EnvVarMissing("PUBLIC_URL".to_string()))?;
+    Ok(public_url.trim_end_matches('/').to_string())
+}
+
+fn cookie(name: &str, value: String, public_url: &str) -> Cookie<'static> {
+    Cookie::build(name.to_string(), value)
+        .path("/compose")
+        .http_only(true)
+        .secure(public_url.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+/// The user signed in with the session cookie, who needs the `send`
+/// permission in Hive like anyone else.
+async fn signed_in(ses: &Client, request: &HttpRequest) -> Result<Authorized<Sender>, Error> {
+    let token = request
+        .cookie(SESSION_COOKIE)
+        .ok_or_else(|| Error::IdTokenInvalid("not signed in".to_string()))?;
+    let username = oidc(ses)?.verify(token.value()).await?;
+    Authorized::check(Principal::User(username)).await
+}
+
+/// An entry in the from picker: a whole address, or a domain the user picks
+/// the name in.
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+struct SenderOption {
+    address: Option<String>,
+    domain: String,
+}
+
+/// What the user may send from, given the scopes of their `send`
+/// permission. Scopes outside the verified domains are left out.
+fn sender_options(scopes: Vec<String>) -> Vec<SenderOption> {
+    scopes
+        .into_iter()
+        .map(|scope| match scope.rsplit_once('@') {
+            Some((_, domain)) => SenderOption {
+                domain: domain.to_lowercase(),
+                address: Some(scope),
+            },
+            None => SenderOption {
+                domain: scope.to_lowercase(),
+                address: None,
+            },
+        })
+        .filter(|option| VerifiedDomains::try_from(option.domain.clone()).is_ok())
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct ComposePage {
+    username: String,
+    senders: Vec<SenderOption>,
+    templates: Vec<String>,
+}
+
+#[get("")]
+async fn compose_page(ses: web::Data<Client>, request: HttpRequest) -> Result<HttpResponse, Error> {
+    let sender = match signed_in(&ses, &request).await {
+        Ok(sender) => sender,
+        Err(Error::IdTokenInvalid(_)) => return Ok(redirect("/compose/login")),
+        Err(e) => return Err(e),
+    };
+    let Principal::User(username) = sender.principal() else {
+        unreachable!("the session is always a user");
+    };
+
+    let scopes = hive::permission_scopes(sender.principal(), Sender::NAME).await?;
+    let page = ComposePage {
+        username: username.clone(),
+        senders: sender_options(scopes),
+        templates: [
+            EmailTemplateTypeLegacy::Default,
+            EmailTemplateTypeLegacy::Metaspexet,
+            EmailTemplateTypeLegacy::None,
+        ]
+        .iter()
+        .map(|template| template.to_string())
+        .collect(),
+    };
+
+    let html = ses.templates.render("compose/page", &page)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+/// Sends the user to the section's login.
+#[get("/login")]
+async fn login(ses: web::Data<Client>) -> Result<HttpResponse, Error> {
+    let public_url = public_url()?;
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let url = oidc(&ses)?
+        .authorization_url(&format!("{}/compose/callback", public_url), &nonce)
+        .await?;
+
+    let mut nonce = cookie(NONCE_COOKIE, nonce, &public_url);
+    nonce.set_max_age(Duration::minutes(10));
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, url))
+        .cookie(nonce)
+        .finish())
+}
+
+/// Where the login sends the user back to. The ID token is in the fragment,
+/// which only the browser sees, so the page posts it on to `/session`.
+#[get("/callback")]
+async fn callback(ses: web::Data<Client>) -> Result<HttpResponse, Error> {
+    let html = ses.templates.render("compose/callback", &())?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+#[derive(serde::Deserialize)]
+struct SessionForm {
+    id_token: String,
+}
+
+#[post("/session")]
+async fn session(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    form: web::Form<SessionForm>,
+) -> Result<HttpResponse, Error> {
+    let public_url = public_url()?;
+    let nonce = request
+        .cookie(NONCE_COOKIE)
+        .map(|nonce| nonce.value().to_string())
+        .unwrap_or_default();
+    let username = oidc(&ses)?.verify_login(&form.id_token, &nonce).await?;
+    Authorized::<Sender>::check(Principal::User(username)).await?;
+
+    let mut nonce = cookie(NONCE_COOKIE, String::new(), &public_url);
+    nonce.make_removal();
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/compose"))
+        .cookie(cookie(
+            SESSION_COOKIE,
+            form.into_inner().id_token,
+            &public_url,
+        ))
+        .cookie(nonce)
+        .finish())
+}
+
+#[derive(serde::Deserialize)]
+struct PreviewRequest {
+    #[serde(default)]
+    template: EmailTemplateTypeLegacy,
+    content: String,
+}
+
+/// The email as recipients would see it, with `content` as markdown.
+#[post("/preview")]
+async fn preview(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    body: web::Json<PreviewRequest>,
+) -> Result<HttpResponse, Error> {
+    signed_in(&ses, &request).await?;
+    let html = ses.render_body(&body.template, &body.content, false, None)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+/// Sends, or schedules with `sendAt`, an email from the compose page. Takes
+/// the same request as `/api/send`.
+#[post("/send")]
+async fn send(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    body: web::Json<EmailRequestLegacy>,
+) -> Result<HttpResponse, Error> {
+    let sender = signed_in(&ses, &request).await?;
+    ses.send_email_legacy(body.into_inner(), sender.principal().clone())
+        .await
+        .map(HttpResponse::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offers_verified_senders() {
+        let scopes = [
+            "d-sys@datasektionen.se",
+            "Metaspexet.se",
+            "kth.se",
+            "x@gmail.com",
+        ];
+        let options = sender_options(scopes.iter().map(|s| s.to_string()).collect());
+        assert_eq!(
+            options,
+            vec![
+                SenderOption {
+                    address: Some("d-sys@datasektionen.se".to_string()),
+                    domain: "datasektionen.se".to_string(),
+                },
+                SenderOption {
+                    address: None,
+                    domain: "metaspexet.se".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::{env, fs};
+
+use crate::cors::CorsConfig;
+use crate::delivery::PriorityConfig;
+use crate::error::Error;
+use crate::forms::FormConfig;
+use crate::idempotency::IdempotencyConfig;
+use crate::limits::LimitsConfig;
+use crate::logging::LoggingConfig;
+use crate::oidc::OidcConfig;
+use crate::quota::QuotaConfig;
+use crate::sandbox::SandboxConfig;
+use crate::staging::RecipientPolicy;
+use crate::telemetry::TracingConfig;
+
+/// Settings that are too structured for environment variables, read from
+/// the TOML file at `SPAM_CONFIG` (default `config.toml`). Every section is
+/// optional.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub logging: LoggingConfig,
+    pub tracing: TracingConfig,
+    pub oidc: OidcConfig,
+    pub cors: CorsConfig,
+    /// Contact forms, by name.
+    pub forms: HashMap<String, FormConfig>,
+    pub limits: LimitsConfig,
+    pub quota: QuotaConfig,
+    pub priority: PriorityConfig,
+    pub idempotency: IdempotencyConfig,
+    pub sandbox: SandboxConfig,
+    pub recipient_policy: RecipientPolicy,
+    /// SES configuration set to send with, by sender domain.
+    pub configuration_sets: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, Error> {
+        let path = env::var("SPAM_CONFIG").ok();
+
+        match fs::read_to_string(path.as_deref().unwrap_or("config.toml")) {
+            Ok(content) => Self::parse(&content),
+            // Running without a config file is fine, unless one was asked for
+            Err(e) if e.kind() == ErrorKind::NotFound && path.is_none() => Ok(Self::default()),
+            Err(e) => Err(Error::Config(format!("Failed to read config: {}", e))),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(|e| Error::Config(format!("Invalid config: {}", e)))
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_cors::Cors;
+use actix_web::http::Method;
+use actix_web::http::header::{self, HeaderMap};
+use serde::Deserializer;
+use serde::de::Error as _;
+
+use crate::error::Error;
+use crate::hive::{self, Principal};
+
+/// Which websites may call spam from a browser. Browsers send the page's
+/// origin with every request, so a key found on some page can't be used
+/// from another site.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins any key may be used from, e.g. `https://datasektionen.se`.
+    /// `*` allows every origin.
+    pub origins: Vec<String>,
+    #[serde(deserialize_with = "methods")]
+    pub methods: Vec<Method>,
+    /// Origins single keys may be used from instead, by key id (see
+    /// [`hive::key_id`]).
+    pub keys: HashMap<String, KeyOrigins>,
+    /// Keys that are meant to be published on a website, by key. They are
+    /// not in Hive, and can only send to fixed recipients.
+    pub browser_keys: HashMap<String, BrowserKey>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            keys: HashMap::new(),
+            browser_keys: HashMap::new(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct KeyOrigins {
+    pub origins: Vec<String>,
+}
+
+/// A publishable key, e.g. for a contact form on a static site.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BrowserKey {
+    /// The only origins the key works from.
+    pub origins: Vec<String>,
+    /// Addresses or domains the key may send from, like the scopes of the
+    /// `send` permission in Hive.
+    pub from: Vec<String>,
+    /// The only addresses the key may send to.
+    pub to: Vec<String>,
+}
+
+fn methods<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Method>, D::Error> {
+    <Vec<String> as serde::Deserialize>::deserialize(deserializer)?
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| D::Error::custom(format!("invalid method {}", method)))
+        })
+        .collect()
+}
+
+/// The `Origin` header, sent by browsers.
+pub fn origin(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+}
+
+fn allows(origins: &[String], origin: &str) -> bool {
+    origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == origin)
+}
+
+impl CorsConfig {
+    /// The browser key `token`, if it is one.
+    pub fn browser_key(&self, token: &str) -> Option<Principal> {
+        self.browser_keys
+            .get(token)
+            .map(|key| Principal::Browser(token.to_string(), key.clone()))
+    }
+
+    /// Checks that `principal` may be used from `origin`, the request's
+    /// `Origin` header. Requests from outside a browser have none, and are
+    /// only turned away for browser keys and forms.
+    pub fn check_origin(&self, principal: &Principal, origin: Option<&str>) -> Result<(), Error> {
+        let origins = match principal {
+            Principal::Browser(_, key) | Principal::Form(_, key) => &key.origins,
+            Principal::Key(key) => self
+                .keys
+                .get(&hive::key_id(key))
+                .map_or(&self.origins, |key| &key.origins),
+            Principal::User(_) => &self.origins,
+        };
+
+        match origin {
+            Some(origin) if allows(origins, origin) => Ok(()),
+            None if matches!(principal, Principal::Key(_) | Principal::User(_)) => Ok(()),
+            origin => Err(Error::OriginNotAllowed(
+                origin.unwrap_or("no origin").to_string(),
+            )),
+        }
+    }
+
+    /// Lets preflight requests through from every origin some key, or one of
+    /// `others` (like forms), may be used from. Which key may be used from
+    /// where is checked along with the key itself.
+    pub fn middleware<'a>(&'a self, others: impl IntoIterator<Item = &'a String>) -> Cors {
+        let cors = Cors::default()
+            .allow_any_header()
+            .allowed_methods(self.methods.clone());
+
+        let origins = self
+            .origins
+            .iter()
+            .chain(self.keys.values().flat_map(|key| &key.origins))
+            .chain(self.browser_keys.values().flat_map(|key| &key.origins))
+            .chain(others)
+            .cloned()
+            .collect::<HashSet<_>>();
+        if origins.contains("*") {
+            return cors.allow_any_origin();
+        }
+        cors.allowed_origin_fn(move |origin, _| {
+            origin.to_str().is_ok_and(|origin| origins.contains(origin))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn config() -> CorsConfig {
+        Config::parse(
+            r#"
+            [cors]
+            origins = ["https://datasektionen.se"]
+            methods = ["get", "post"]
+
+            [cors.keys.7a0d91593df9293a]
+            origins = ["https://ddagen.se"]
+
+            [cors.browser_keys.pk_contact]
+            origins = ["https://metaspexet.se"]
+            from = ["kontakt@metaspexet.se"]
+            to = ["styrelsen@metaspexet.se"]
+            "#,
+        )
+        .unwrap()
+        .cors
+    }
+
+    #[test]
+    fn checks_origins_per_key() {
+        let config = config();
+        assert_eq!(config.methods, vec![Method::GET, Method::POST]);
+
+        let key = Principal::Key("secret".to_string());
+        assert!(config.check_origin(&key, None).is_ok());
+        assert!(
+            config
+                .check_origin(&key, Some("https://datasektionen.se"))
+                .is_ok()
+        );
+        assert!(config.check_origin(&key, Some("https://evil.com")).is_err());
+
+        let restricted = Principal::Key("restricted".to_string());
+        assert!(
+            config
+                .check_origin(&restricted, Some("https://ddagen.se"))
+                .is_ok()
+        );
+        assert!(
+            config
+                .check_origin(&restricted, Some("https://datasektionen.se"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn browser_keys_need_their_origin() {
+        let config = config();
+        assert!(config.browser_key("secret").is_none());
+
+        let browser = config.browser_key("pk_contact").unwrap();
+        assert!(
+            config
+                .check_origin(&browser, Some("https://metaspexet.se"))
+                .is_ok()
+        );
+        assert!(
+            config
+                .check_origin(&browser, Some("https://datasektionen.se"))
+                .is_err()
+        );
+        assert!(config.check_origin(&browser, None).is_err());
+    }
+}
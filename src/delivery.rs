@@ -0,0 +1,664 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::HttpResponse;
+use aws_sdk_sesv2::types::{Destination, Message, MessageTag};
+
+use crate::address::{self, Mailbox};
+use crate::auth::{Permission, Sender};
+use crate::error::Error;
+use crate::hive::{self, Principal};
+use crate::{Client, VerifiedDomains, mime};
+
+/// SES accepts at most this many recipients per `SendEmail` call.
+pub const SES_MAX_DESTINATIONS: usize = 50;
+
+/// How many `SendEmail` calls a single request may have in flight at once.
+pub const MAX_CONCURRENT_SENDS: usize = 10;
+
+/// Used until SES has told us the account's actual `MaxSendRate`. This is what
+/// new production accounts start out with.
+const DEFAULT_MAX_SEND_RATE: f64 = 14.0;
+
+/// Hive permission needed to send transactional mail, since it bypasses
+/// every limit.
+const TRANSACTIONAL_PERMISSION: &str = "transactional";
+
+/// How urgent an email is, which decides how it shares the account's sending
+/// rate and quota with everything else.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Mail someone is waiting for, like password resets. Skips pacing,
+    /// rate limits and quotas.
+    Transactional,
+    #[default]
+    Normal,
+    /// Newsletters and other mass mail. Gets a smaller share of the sending
+    /// rate, and is queued instead of rejected when the quota runs low.
+    Bulk,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Transactional => "transactional",
+            Priority::Normal => "normal",
+            Priority::Bulk => "bulk",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PriorityConfig {
+    /// Share of `MaxSendRate` that bulk mail may use.
+    pub bulk_rate: f64,
+    /// SES configuration set to send bulk mail with, to keep its reputation
+    /// data apart from other mail.
+    pub bulk_configuration_set: Option<String>,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            bulk_rate: 0.5,
+            bulk_configuration_set: None,
+        }
+    }
+}
+
+/// How a message is sent, apart from who it is from and to.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    pub domain: VerifiedDomains,
+    /// Name of the template, for metrics.
+    pub template: String,
+    pub priority: Priority,
+    pub configuration_set: Option<String>,
+    pub tags: Vec<MessageTag>,
+}
+
+/// Whether a send still has to be let through the quota and the key's
+/// limits, or already was when it was queued.
+#[derive(Debug, Clone)]
+pub enum Admission {
+    Request { principal: Principal },
+    Queued { key_id: String },
+}
+
+impl Admission {
+    pub fn key_id(&self) -> String {
+        match self {
+            Admission::Request { principal } => principal.id(),
+            Admission::Queued { key_id } => key_id.clone(),
+        }
+    }
+
+    /// Checks that the email may be sent from `from`. Keys may send from any
+    /// verified domain, but signed-in users only from the addresses and
+    /// domains their `send` permission is scoped to in Hive, and browser keys
+    /// and forms only from those they are configured with.
+    pub async fn authorize_from(&self, from: &str) -> Result<(), Error> {
+        let scopes = match self {
+            Admission::Request {
+                principal: principal @ Principal::User(_),
+            } => hive::permission_scopes(principal, Sender::NAME).await?,
+            Admission::Request {
+                principal: Principal::Browser(_, key) | Principal::Form(_, key),
+            } => key.from.clone(),
+            _ => return Ok(()),
+        };
+
+        if !sender_in_scopes(from, &scopes) {
+            return Err(Error::SenderNotAllowed(format!(
+                "{} may not send from {}",
+                self.key_id(),
+                from
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that a browser key or form only sends to its fixed recipients,
+    /// and never to a mailing list. Anyone else may send to anyone.
+    pub fn authorize_recipients(&self, recipients: &[String], list: bool) -> Result<(), Error> {
+        let Admission::Request {
+            principal: Principal::Browser(_, key) | Principal::Form(_, key),
+        } = self
+        else {
+            return Ok(());
+        };
+
+        if list {
+            return Err(Error::List(format!(
+                "{} can't send to mailing lists",
+                self.key_id()
+            )));
+        }
+        match recipients
+            .iter()
+            .find(|recipient| !key.to.iter().any(|to| to.eq_ignore_ascii_case(recipient)))
+        {
+            Some(recipient) => Err(Error::InvalidAddress(format!(
+                "{} can't send to {}",
+                self.key_id(),
+                recipient
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether `from` is one of `scopes`, or has one of them as its domain.
+fn sender_in_scopes(from: &str, scopes: &[String]) -> bool {
+    let from = from.trim();
+    let domain = from.rsplit_once('@').map(|(_, domain)| domain);
+    scopes.iter().any(|scope| {
+        scope.eq_ignore_ascii_case(from)
+            || domain.is_some_and(|domain| scope.eq_ignore_ascii_case(domain))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admitted {
+    Now,
+    /// There is no room for the email right now, put it in the queue.
+    Later,
+}
+
+/// The recipients of one `SendEmail` call.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Batch {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+}
+
+impl Batch {
+    pub fn len(&self) -> usize {
+        self.to.len() + self.cc.len() + self.bcc.len()
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.to
+            .iter()
+            .chain(&self.cc)
+            .chain(&self.bcc)
+            .map(|mailbox| bare_address(mailbox))
+            .collect()
+    }
+
+    pub fn destination(&self) -> Destination {
+        let some = |addrs: &Vec<String>| Some(addrs.clone()).filter(|a| !a.is_empty());
+        Destination::builder()
+            .set_to_addresses(some(&self.to))
+            .set_cc_addresses(some(&self.cc))
+            .set_bcc_addresses(some(&self.bcc))
+            .build()
+    }
+}
+
+/// Splits each entry of a recipient field into its mailboxes, since legacy
+/// requests may put several addresses in one comma-separated string.
+fn mailboxes(field: Option<Vec<String>>) -> Result<Vec<String>, Error> {
+    let mut mailboxes = Vec::new();
+    for addrs in field.into_iter().flatten() {
+        mailboxes.extend(address::parse_list(&addrs)?.iter().map(Mailbox::to_string));
+    }
+    Ok(mailboxes)
+}
+
+/// The bare address of a mailbox that was already parsed, e.g. one in a
+/// [`Batch`].
+fn bare_address(mailbox: &str) -> String {
+    address::parse(mailbox).map_or_else(|_| mailbox.to_string(), |mailbox| mailbox.address())
+}
+
+/// Splits the recipients of an email into batches that SES accepts.
+///
+/// If `to` and `cc` fit in a single batch they are kept visible in the first
+/// one, and `bcc` fills up the rest. Otherwise everyone is moved to `bcc`, so
+/// that no recipient gets the email twice and nobody sees the whole list.
+pub fn batches(
+    to: Option<Vec<String>>,
+    cc: Option<Vec<String>>,
+    bcc: Option<Vec<String>>,
+) -> Result<Vec<Batch>, Error> {
+    let (to, cc, bcc) = (mailboxes(to)?, mailboxes(cc)?, mailboxes(bcc)?);
+
+    if to.len() + cc.len() + bcc.len() <= SES_MAX_DESTINATIONS {
+        return Ok(vec![Batch { to, cc, bcc }]);
+    }
+
+    let (mut first, rest) = if to.len() + cc.len() < SES_MAX_DESTINATIONS {
+        (
+            Batch {
+                to,
+                cc,
+                bcc: vec![],
+            },
+            bcc,
+        )
+    } else {
+        (Batch::default(), [to, cc, bcc].concat())
+    };
+
+    let mut rest = rest.into_iter();
+    first
+        .bcc
+        .extend(rest.by_ref().take(SES_MAX_DESTINATIONS - first.len()));
+
+    let rest = rest.collect::<Vec<_>>();
+    Ok(std::iter::once(first)
+        .chain(rest.chunks(SES_MAX_DESTINATIONS).map(|bcc| Batch {
+            bcc: bcc.to_vec(),
+            ..Default::default()
+        }))
+        .collect())
+}
+
+/// Spaces out calls to SES so that we stay under the account's
+/// `MaxSendRate`, which counts every recipient as one email.
+#[derive(Debug)]
+pub struct Pacer {
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Self {
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserves room for `recipients` emails, returning how long to wait
+    /// before sending them.
+    fn reserve(&self, rate: f64, recipients: usize) -> Duration {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(recipients.max(1) as f64 / rate.max(0.1));
+        start - now
+    }
+}
+
+impl Client {
+    /// Waits until `recipients` more emails can be sent without going over
+    /// `MaxSendRate`, and counts them towards the 24 hour quota.
+    ///
+    /// Transactional mail never waits, but still takes up room so that
+    /// other mail makes way for it. Bulk mail first waits for its own,
+    /// smaller, share of the rate.
+    pub async fn pace(&self, recipients: usize, priority: Priority) {
+        let rate = self
+            .quota
+            .get()
+            .map(|quota| quota.max_send_rate)
+            .unwrap_or(DEFAULT_MAX_SEND_RATE);
+        self.quota.record(recipients);
+
+        if priority == Priority::Bulk {
+            let bulk_rate = rate * self.config.priority.bulk_rate.clamp(0.0, 1.0);
+            let wait = self.bulk_pacer.reserve(bulk_rate, recipients);
+            if !wait.is_zero() {
+                actix_web::rt::time::sleep(wait).await;
+            }
+        }
+
+        let wait = self.pacer.reserve(rate, recipients);
+        if !wait.is_zero() && priority != Priority::Transactional {
+            actix_web::rt::time::sleep(wait).await;
+        }
+    }
+
+    /// The configuration set to send mail of `priority` from `domain` with,
+    /// if any. Bulk mail's own configuration set takes precedence over the
+    /// domain's.
+    pub fn configuration_set(
+        &self,
+        priority: Priority,
+        domain: &VerifiedDomains,
+    ) -> Option<String> {
+        let bulk = match priority {
+            Priority::Bulk => self.config.priority.bulk_configuration_set.clone(),
+            Priority::Transactional | Priority::Normal => None,
+        };
+        bulk.or_else(|| self.config.configuration_sets.get(domain.as_str()).cloned())
+    }
+
+    pub fn send_options(
+        &self,
+        priority: Priority,
+        domain: VerifiedDomains,
+        template: String,
+        tags: Vec<MessageTag>,
+    ) -> SendOptions {
+        SendOptions {
+            configuration_set: self.configuration_set(priority, &domain),
+            domain,
+            template,
+            priority,
+            tags,
+        }
+    }
+
+    /// Lets a send to `recipients` recipients through the SES quota and the
+    /// key's limits, counting it towards them.
+    ///
+    /// Bulk mail that doesn't fit in the quota is admitted for later, so
+    /// that it can be queued. Queued mail has already been counted against
+    /// the key's limits, so only the quota is checked again.
+    pub async fn admit(
+        &self,
+        admission: &Admission,
+        recipients: usize,
+        priority: Priority,
+    ) -> Result<Admitted, Error> {
+        match (admission, priority) {
+            (Admission::Request { principal }, Priority::Transactional) => {
+                hive::require_permission(principal, TRANSACTIONAL_PERMISSION).await?;
+                self.record_usage(principal, recipients)?;
+                Ok(Admitted::Now)
+            }
+            (Admission::Request { principal }, Priority::Normal) => {
+                self.check_quota(recipients)?;
+                self.enforce_limits(principal, recipients).await?;
+                Ok(Admitted::Now)
+            }
+            (Admission::Request { principal }, Priority::Bulk) => {
+                self.enforce_limits(principal, recipients).await?;
+                match self.check_quota(recipients) {
+                    Ok(()) => Ok(Admitted::Now),
+                    Err(Error::SendQuotaExceeded(_, _)) => Ok(Admitted::Later),
+                    Err(e) => Err(e),
+                }
+            }
+            (Admission::Queued { .. }, Priority::Transactional) => Ok(Admitted::Now),
+            (Admission::Queued { .. }, Priority::Normal | Priority::Bulk) => {
+                self.check_quota(recipients)?;
+                Ok(Admitted::Now)
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BatchResult {
+    pub recipients: Vec<String>,
+    #[serde(rename = "messageId", skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct SendSummary {
+    /// Number of recipients the email was accepted for.
+    pub sent: usize,
+    /// Number of recipients the email could not be sent to.
+    pub failed: usize,
+    pub batches: Vec<BatchResult>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct QueuedResponse {
+    /// Id of the email in the queue.
+    pub queued: i64,
+}
+
+/// An email a dry run would have sent.
+#[derive(serde::Serialize, Debug)]
+pub struct DryRunMessage {
+    pub recipients: Vec<String>,
+    pub mime: String,
+}
+
+impl DryRunMessage {
+    pub fn new(
+        from: &str,
+        dest: &Destination,
+        reply_to: Option<&[String]>,
+        message: &Message,
+    ) -> Self {
+        Self {
+            recipients: [
+                dest.to_addresses(),
+                dest.cc_addresses(),
+                dest.bcc_addresses(),
+            ]
+            .concat()
+            .iter()
+            .map(|mailbox| bare_address(mailbox))
+            .collect(),
+            mime: mime::render(from, dest, reply_to, message),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct DryRunResponse {
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    pub messages: Vec<DryRunMessage>,
+}
+
+/// What a send request resulted in. Emails that fit in one `SendEmail` call
+/// respond with just the message ID, like they always have.
+#[derive(Debug)]
+pub enum SendOutcome {
+    Single(String),
+    Batched(SendSummary),
+    Queued(i64),
+    /// Nothing was sent, see [`crate::sandbox`].
+    DryRun(Vec<DryRunMessage>),
+}
+
+impl SendOutcome {
+    /// Aggregates the results of several `SendEmail` calls. If none of them
+    /// succeeded, the first error is returned instead.
+    pub fn batched(results: Vec<(Vec<String>, Result<String, Error>)>) -> Result<Self, Error> {
+        if !results.is_empty() && results.iter().all(|(_, result)| result.is_err()) {
+            let mut errors = results.into_iter().filter_map(|(_, result)| result.err());
+            return Err(errors.next().expect("results is not empty"));
+        }
+
+        let mut summary = SendSummary {
+            sent: 0,
+            failed: 0,
+            batches: Vec::with_capacity(results.len()),
+        };
+        for (recipients, result) in results {
+            let batch = match result {
+                Ok(message_id) => {
+                    summary.sent += recipients.len();
+                    BatchResult {
+                        recipients,
+                        message_id: Some(message_id),
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    summary.failed += recipients.len();
+                    BatchResult {
+                        recipients,
+                        message_id: None,
+                        error: Some(e.to_string()),
+                    }
+                }
+            };
+            summary.batches.push(batch);
+        }
+
+        Ok(SendOutcome::Batched(summary))
+    }
+}
+
+impl From<SendOutcome> for HttpResponse {
+    fn from(outcome: SendOutcome) -> Self {
+        match outcome {
+            SendOutcome::Single(message_id) => HttpResponse::Ok().body(message_id),
+            SendOutcome::Batched(summary) => HttpResponse::Ok().json(summary),
+            SendOutcome::Queued(id) => HttpResponse::Accepted().json(QueuedResponse { queued: id }),
+            SendOutcome::DryRun(messages) => HttpResponse::Ok().json(DryRunResponse {
+                dry_run: true,
+                messages,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cors::BrowserKey;
+
+    fn addresses(prefix: &str, n: usize) -> Option<Vec<String>> {
+        Some(
+            (0..n)
+                .map(|i| format!("{}{}@datasektionen.se", prefix, i))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn matches_sender_scopes() {
+        let scopes = vec![
+            "d-sys@datasektionen.se".to_string(),
+            "metaspexet.se".to_string(),
+        ];
+        assert!(sender_in_scopes("D-Sys@datasektionen.se", &scopes));
+        assert!(sender_in_scopes("info@metaspexet.se", &scopes));
+        assert!(!sender_in_scopes("ordf@datasektionen.se", &scopes));
+        assert!(!sender_in_scopes("datasektionen.se", &[]));
+    }
+
+    #[test]
+    fn holds_browser_keys_to_their_recipients() {
+        let admission = Admission::Request {
+            principal: Principal::Browser(
+                "pk_contact".to_string(),
+                BrowserKey {
+                    to: vec!["styrelsen@metaspexet.se".to_string()],
+                    ..Default::default()
+                },
+            ),
+        };
+        let to = |addresses: &[&str]| addresses.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        assert!(
+            admission
+                .authorize_recipients(&to(&["Styrelsen@metaspexet.se"]), false)
+                .is_ok()
+        );
+        assert!(
+            admission
+                .authorize_recipients(&to(&["styrelsen@metaspexet.se", "x@gmail.com"]), false)
+                .is_err()
+        );
+        assert!(admission.authorize_recipients(&[], true).is_err());
+
+        let key = Admission::Request {
+            principal: Principal::Key("secret".to_string()),
+        };
+        assert!(
+            key.authorize_recipients(&to(&["x@gmail.com"]), true)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn small_send_is_one_batch() {
+        let batches =
+            batches(addresses("to", 2), addresses("cc", 1), addresses("bcc", 47)).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].to.len(), 2);
+        assert_eq!(batches[0].bcc.len(), 47);
+    }
+
+    #[test]
+    fn splits_comma_separated_entries() {
+        let to = Some(vec![
+            "a@datasektionen.se, \"B, C\" <b@datasektionen.se>".to_string(),
+        ]);
+        let batches = batches(to, None, None).unwrap();
+        assert_eq!(batches[0].to.len(), 2);
+    }
+
+    #[test]
+    fn keeps_to_and_cc_in_first_batch() {
+        let batches = batches(
+            addresses("to", 2),
+            addresses("cc", 3),
+            addresses("bcc", 120),
+        )
+        .unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].to.len(), 2);
+        assert_eq!(batches[0].cc.len(), 3);
+        assert_eq!(batches[0].len(), SES_MAX_DESTINATIONS);
+        assert!(
+            batches[1..]
+                .iter()
+                .all(|b| b.to.is_empty() && b.cc.is_empty())
+        );
+        assert_eq!(batches.iter().map(Batch::len).sum::<usize>(), 125);
+    }
+
+    #[test]
+    fn moves_everyone_to_bcc_when_to_is_large() {
+        let batches = batches(addresses("to", 120), None, addresses("bcc", 5)).unwrap();
+        assert_eq!(batches.len(), 3);
+        assert!(batches.iter().all(|b| b.to.is_empty()));
+        assert!(batches.iter().all(|b| b.len() <= SES_MAX_DESTINATIONS));
+        assert_eq!(batches.iter().map(Batch::len).sum::<usize>(), 125);
+    }
+
+    #[test]
+    fn paces_by_recipients() {
+        let pacer = Pacer::new();
+        assert!(pacer.reserve(10.0, 5).is_zero());
+        let wait = pacer.reserve(10.0, 5);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn all_failed_is_an_error() {
+        let results = vec![
+            (
+                vec!["a@datasektionen.se".to_string()],
+                Err(Error::MissingContent),
+            ),
+            (
+                vec!["b@datasektionen.se".to_string()],
+                Err(Error::MissingContent),
+            ),
+        ];
+        assert!(SendOutcome::batched(results).is_err());
+    }
+
+    #[test]
+    fn partial_failure_is_summarized() {
+        let results = vec![
+            (
+                vec!["a@datasektionen.se".to_string()],
+                Ok("message-id".to_string()),
+            ),
+            (
+                vec!["b@datasektionen.se".to_string()],
+                Err(Error::MissingContent),
+            ),
+        ];
+        let Ok(SendOutcome::Batched(summary)) = SendOutcome::batched(results) else {
+            panic!("Expected a summary");
+        };
+        assert_eq!(summary.sent, 1);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(
+            summary.batches[1].error.as_deref(),
+            Some(&*Error::MissingContent.to_string())
+        );
+    }
+}
@@ -1,8 +1,12 @@
 use aws_sdk_sesv2 as sesv2;
-use log::error;
+use tracing::error;
+
+use crate::logging;
+use crate::metrics::METRICS;
 use std::fmt::Display;
 
-use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use actix_web::http::{StatusCode, header};
+use actix_web::{HttpResponse, ResponseError};
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,14 +15,97 @@ pub enum Error {
     InvalidContentType,
     ApiKeyInvalid,
     ApiKeyLookup(String),
+    IdTokenInvalid(String),
+    IdTokenLookup(String),
+    SenderNotAllowed(String),
+    OriginNotAllowed(String),
+    InvalidForm(String),
+    CaptchaInvalid(String),
+    CaptchaLookup(String),
     MissingContent,
     EmailSend(String),
     TemplateRender(String),
     TemplateLoad(String),
+    InvalidTemplate(String),
     Attachment(String),
-    NotASCII(String),
     InvalidAddress(String),
     EmailBody(String),
+    RawMessage(String),
+    InvalidHeader(String),
+    InvalidTag(String),
+    InvalidToken,
+    Store(String),
+    List(String),
+    NotFound(String),
+    Conflict(String),
+    Config(String),
+    RateLimited(String, u64),
+    SendQuotaExceeded(String, u64),
+    Suppression(String),
+    SesRequest(String),
+}
+
+impl Error {
+    /// Whether the message may hold addresses or what was written in a form,
+    /// which are kept out of the logs while redaction is on.
+    fn is_personal(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidAddress(_)
+                | Error::InvalidForm(_)
+                | Error::SenderNotAllowed(_)
+                | Error::RawMessage(_)
+        )
+    }
+
+    /// The error as it should be logged, which for errors about personal
+    /// data is only its name while redaction is on.
+    pub fn redacted(&self) -> String {
+        if logging::redact() && self.is_personal() {
+            format!("{}: <redacted>", self.name())
+        } else {
+            self.to_string()
+        }
+    }
+
+    /// The name of the variant, for use as a metric label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::EnvVarMissing(_) => "EnvVarMissing",
+            Error::InvalidEmailDomain(_) => "InvalidEmailDomain",
+            Error::InvalidContentType => "InvalidContentType",
+            Error::ApiKeyInvalid => "ApiKeyInvalid",
+            Error::ApiKeyLookup(_) => "ApiKeyLookup",
+            Error::IdTokenInvalid(_) => "IdTokenInvalid",
+            Error::IdTokenLookup(_) => "IdTokenLookup",
+            Error::SenderNotAllowed(_) => "SenderNotAllowed",
+            Error::OriginNotAllowed(_) => "OriginNotAllowed",
+            Error::InvalidForm(_) => "InvalidForm",
+            Error::CaptchaInvalid(_) => "CaptchaInvalid",
+            Error::CaptchaLookup(_) => "CaptchaLookup",
+            Error::MissingContent => "MissingContent",
+            Error::EmailSend(_) => "EmailSend",
+            Error::TemplateRender(_) => "TemplateRender",
+            Error::TemplateLoad(_) => "TemplateLoad",
+            Error::InvalidTemplate(_) => "InvalidTemplate",
+            Error::Attachment(_) => "Attachment",
+            Error::InvalidAddress(_) => "InvalidAddress",
+            Error::EmailBody(_) => "EmailBody",
+            Error::RawMessage(_) => "RawMessage",
+            Error::InvalidHeader(_) => "InvalidHeader",
+            Error::InvalidTag(_) => "InvalidTag",
+            Error::InvalidToken => "InvalidToken",
+            Error::Store(_) => "Store",
+            Error::List(_) => "List",
+            Error::NotFound(_) => "NotFound",
+            Error::Conflict(_) => "Conflict",
+            Error::Config(_) => "Config",
+            Error::RateLimited(_, _) => "RateLimited",
+            Error::SendQuotaExceeded(_, _) => "SendQuotaExceeded",
+            Error::Suppression(_) => "Suppression",
+            Error::SesRequest(_) => "SesRequest",
+        }
+    }
 }
 
 impl From<sesv2::Error> for Error {
@@ -39,22 +126,52 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Store(err.to_string())
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::EnvVarMissing(msg) => write!(f, "Environment variable missing: {}", msg),
             Error::ApiKeyInvalid => write!(f, "API key is invalid or lacks permissions"),
             Error::ApiKeyLookup(msg) => write!(f, "API lookup failed: {}", msg),
+            Error::IdTokenInvalid(msg) => write!(f, "ID token is invalid: {}", msg),
+            Error::IdTokenLookup(msg) => write!(f, "ID token lookup failed: {}", msg),
+            Error::SenderNotAllowed(msg) => write!(f, "Sender not allowed: {}", msg),
+            Error::OriginNotAllowed(origin) => write!(f, "Origin not allowed: {}", origin),
+            Error::InvalidForm(msg) => write!(f, "Invalid form: {}", msg),
+            Error::CaptchaInvalid(msg) => write!(f, "Captcha failed: {}", msg),
+            Error::CaptchaLookup(msg) => write!(f, "Captcha verification failed: {}", msg),
             Error::InvalidEmailDomain(domain) => write!(f, "Invalid email domain: {}", domain),
             Error::InvalidContentType => write!(f, "Invalid content type"),
             Error::EmailSend(msg) => write!(f, "Failed to send email: {}", msg),
             Error::TemplateRender(msg) => write!(f, "Failed to render template: {}", msg),
             Error::TemplateLoad(msg) => write!(f, "Failed to load template: {}", msg),
+            Error::InvalidTemplate(msg) => write!(f, "Invalid template: {}", msg),
             Error::Attachment(msg) => write!(f, "Failed to process attachment: {}", msg),
             Error::EmailBody(msg) => write!(f, "Failed to process email body: {}", msg),
-            Error::NotASCII(field) => write!(f, "Contains non-ASCII characters: {}", field),
             Error::MissingContent => write!(f, "No 'html' or 'content' field provided."),
             Error::InvalidAddress(msg) => write!(f, "Invalid address: {}", msg),
+            Error::RawMessage(msg) => write!(f, "Failed to process raw message: {}", msg),
+            Error::InvalidHeader(msg) => write!(f, "Invalid header: {}", msg),
+            Error::InvalidTag(msg) => write!(f, "Invalid tag: {}", msg),
+            Error::InvalidToken => write!(f, "Invalid or malformed token"),
+            Error::Store(msg) => write!(f, "Storage failure: {}", msg),
+            Error::List(msg) => write!(f, "Invalid list: {}", msg),
+            Error::NotFound(what) => write!(f, "Not found: {}", what),
+            Error::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            Error::Config(msg) => write!(f, "Configuration error: {}", msg),
+            Error::RateLimited(what, retry_after) => write!(
+                f,
+                "Rate limited: {}, retry after {} seconds",
+                what, retry_after
+            ),
+            Error::SendQuotaExceeded(msg, _) => write!(f, "SES sending quota reached: {}", msg),
+            Error::Suppression(msg) => write!(f, "Invalid suppression: {}", msg),
+            Error::SesRequest(msg) => write!(f, "SES request failed: {}", msg),
         }
     }
 }
@@ -62,42 +179,87 @@ impl Display for Error {
 impl From<&Error> for HttpResponse {
     fn from(val: &Error) -> Self {
         match val {
-            Error::ApiKeyInvalid => HttpResponse::Unauthorized().body(val.to_string()),
+            Error::ApiKeyInvalid | Error::IdTokenInvalid(_) => {
+                HttpResponse::Unauthorized().body(val.to_string())
+            }
+            Error::SenderNotAllowed(_) | Error::OriginNotAllowed(_) | Error::CaptchaInvalid(_) => {
+                HttpResponse::Forbidden().body(val.to_string())
+            }
+            Error::NotFound(_) => HttpResponse::NotFound().body(val.to_string()),
+            Error::Conflict(_) => HttpResponse::Conflict().body(val.to_string()),
+            Error::RateLimited(_, retry_after) => HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .body(val.to_string()),
+            Error::SendQuotaExceeded(_, retry_after) => HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .body(val.to_string()),
             Error::EmailSend(_)
             | Error::TemplateRender(_)
             | Error::TemplateLoad(_)
             | Error::ApiKeyLookup(_)
-            | Error::EnvVarMissing(_) => HttpResponse::InternalServerError().body(val.to_string()),
+            | Error::IdTokenLookup(_)
+            | Error::CaptchaLookup(_)
+            | Error::EnvVarMissing(_)
+            | Error::Store(_)
+            | Error::Config(_)
+            | Error::SesRequest(_) => HttpResponse::InternalServerError().body(val.to_string()),
             Error::Attachment(_)
             | Error::EmailBody(_)
             | Error::InvalidEmailDomain(_)
             | Error::InvalidContentType
-            | Error::NotASCII(_)
             | Error::MissingContent
-            | Error::InvalidAddress(_) => HttpResponse::BadRequest().body(val.to_string()),
+            | Error::InvalidAddress(_)
+            | Error::RawMessage(_)
+            | Error::InvalidHeader(_)
+            | Error::InvalidTag(_)
+            | Error::InvalidTemplate(_)
+            | Error::InvalidToken
+            | Error::List(_)
+            | Error::InvalidForm(_)
+            | Error::Suppression(_) => HttpResponse::BadRequest().body(val.to_string()),
         }
     }
 }
 
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
-        error!("Response Error: {}", self);
+        error!("Response Error: {}", self.redacted());
+        METRICS.errors.with_label_values(&[self.name()]).inc();
         HttpResponse::from(self)
     }
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
-            Error::ApiKeyInvalid => StatusCode::UNAUTHORIZED,
+            Error::ApiKeyInvalid | Error::IdTokenInvalid(_) => StatusCode::UNAUTHORIZED,
+            Error::SenderNotAllowed(_) | Error::OriginNotAllowed(_) | Error::CaptchaInvalid(_) => {
+                StatusCode::FORBIDDEN
+            }
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::RateLimited(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            Error::SendQuotaExceeded(_, _) => StatusCode::SERVICE_UNAVAILABLE,
             Error::EmailSend(_)
             | Error::TemplateRender(_)
             | Error::TemplateLoad(_)
             | Error::ApiKeyLookup(_)
-            | Error::EnvVarMissing(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            | Error::IdTokenLookup(_)
+            | Error::CaptchaLookup(_)
+            | Error::EnvVarMissing(_)
+            | Error::Store(_)
+            | Error::Config(_)
+            | Error::SesRequest(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Attachment(_)
             | Error::EmailBody(_)
             | Error::InvalidEmailDomain(_)
             | Error::InvalidContentType
-            | Error::NotASCII(_)
             | Error::InvalidAddress(_)
+            | Error::RawMessage(_)
+            | Error::InvalidHeader(_)
+            | Error::InvalidTag(_)
+            | Error::InvalidTemplate(_)
+            | Error::InvalidToken
+            | Error::List(_)
+            | Error::InvalidForm(_)
+            | Error::Suppression(_)
             | Error::MissingContent => StatusCode::BAD_REQUEST,
         }
     }
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::env;
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use handlebars::Handlebars;
+use tracing::info;
+
+use crate::Client;
+use crate::address;
+use crate::cors::{self, BrowserKey};
+use crate::error::Error;
+use crate::hive::Principal;
+use crate::legacy::email::{
+    AddressFieldLegacy, EmailRequestLegacy, EmailTemplateTypeLegacy, ListNameLegacy,
+};
+
+/// A contact form that a static site can post to `/api/forms/<name>`
+/// without a key. Everything about the email but the fields is set here.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FormConfig {
+    pub from: String,
+    pub to: Vec<String>,
+    /// The only origins the form can be posted from.
+    pub origins: Vec<String>,
+    /// Fields the form may have, in the order they are put in the email.
+    pub fields: Vec<String>,
+    /// Handlebars template for the subject, given the fields.
+    pub subject: String,
+    /// Field holding the address to reply to.
+    pub reply_to: Option<String>,
+    /// Field hidden from people, that has to be left empty.
+    pub honeypot: Option<String>,
+    pub captcha: Option<CaptchaConfig>,
+    /// Where to send the browser once the form has been posted.
+    pub redirect: Option<String>,
+    pub template: EmailTemplateTypeLegacy,
+}
+
+/// A captcha checked with its provider's `siteverify` endpoint, which
+/// hCaptcha, Turnstile and reCAPTCHA all have.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct CaptchaConfig {
+    pub verify_url: String,
+    /// Environment variable holding the secret.
+    #[serde(default = "default_secret_env")]
+    pub secret_env: String,
+    /// Field holding the captcha token.
+    #[serde(default = "default_captcha_field")]
+    pub field: String,
+}
+
+fn default_secret_env() -> String {
+    "CAPTCHA_SECRET".to_string()
+}
+
+fn default_captcha_field() -> String {
+    "captcha".to_string()
+}
+
+#[derive(serde::Deserialize)]
+struct CaptchaResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+impl FormConfig {
+    fn principal(&self, name: &str) -> Principal {
+        Principal::Form(
+            name.to_string(),
+            BrowserKey {
+                origins: self.origins.clone(),
+                from: vec![self.from.clone()],
+                to: self.to.clone(),
+            },
+        )
+    }
+
+    fn allows_field(&self, field: &str) -> bool {
+        self.fields.iter().any(|allowed| allowed == field)
+            || self.honeypot.as_deref() == Some(field)
+            || self
+                .captcha
+                .as_ref()
+                .is_some_and(|captcha| captcha.field == field)
+    }
+
+    /// The email for a posted form. Values are escaped, as they come from
+    /// anyone.
+    fn message(&self, values: &HashMap<String, String>) -> Result<EmailRequestLegacy, Error> {
+        if let Some(field) = values.keys().find(|field| !self.allows_field(field)) {
+            return Err(Error::InvalidForm(format!("unknown field {}", field)));
+        }
+
+        let reply_to = match &self.reply_to {
+            Some(field) => match values.get(field).map(|value| value.trim()) {
+                Some("") | None => None,
+                Some(value) if address::parse(value).is_ok() => Some(ListNameLegacy::Name(
+                    AddressFieldLegacy::Address(value.to_string()),
+                )),
+                Some(value) => {
+                    return Err(Error::InvalidForm(format!("invalid {}: {}", field, value)));
+                }
+            },
+            None => None,
+        };
+
+        let mut subject = Handlebars::new();
+        subject.register_escape_fn(handlebars::no_escape);
+        let subject = subject
+            .render_template(&self.subject, values)
+            .map_err(|e| Error::TemplateRender(e.to_string()))?;
+
+        let html = self
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let value = values.get(field).filter(|value| !value.trim().is_empty())?;
+                Some(format!(
+                    "<p><strong>{}</strong><br>{}</p>",
+                    handlebars::html_escape(field),
+                    handlebars::html_escape(value.trim()).replace('\n', "<br>")
+                ))
+            })
+            .collect::<String>();
+
+        Ok(EmailRequestLegacy {
+            key: String::new(),
+            template: self.template.clone(),
+            from: AddressFieldLegacy::Address(self.from.clone()),
+            reply_to,
+            to: Some(ListNameLegacy::List(
+                self.to
+                    .iter()
+                    .map(|to| AddressFieldLegacy::Address(to.clone()))
+                    .collect(),
+            )),
+            // Keeps the subject to one line
+            subject: subject.split_whitespace().collect::<Vec<_>>().join(" "),
+            content: None,
+            html: Some(html),
+            cc: None,
+            bcc: None,
+            attachments: None,
+            headers: None,
+            list_unsubscribe: None,
+            list: None,
+            batching: Default::default(),
+            priority: Default::default(),
+            tags: None,
+            send_at: None,
+            dry_run: false,
+        })
+    }
+
+    fn done(&self) -> HttpResponse {
+        match &self.redirect {
+            Some(location) => HttpResponse::SeeOther()
+                .insert_header((header::LOCATION, location.as_str()))
+                .finish(),
+            None => HttpResponse::Ok().body("Sent"),
+        }
+    }
+}
+
+impl CaptchaConfig {
+    fn secret(&self) -> Result<String, Error> {
+        env::var(&self.secret_env).map_err(|_| Error::EnvVarMissing(self.secret_env.clone()))
+    }
+
+    /// Checks `token` with the captcha provider.
+    async fn verify(
+        &self,
+        secret: &str,
+        token: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<(), Error> {
+        if token.is_empty() {
+            return Err(Error::CaptchaInvalid("no captcha token".to_string()));
+        }
+
+        let mut params = vec![("secret", secret), ("response", token)];
+        if let Some(remote_ip) = remote_ip {
+            params.push(("remoteip", remote_ip));
+        }
+        let res = async {
+            reqwest::Client::new()
+                .post(&self.verify_url)
+                .form(&params)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        }
+        .await
+        .map_err(|e| Error::CaptchaLookup(e.to_string()))?;
+
+        let res: CaptchaResponse =
+            serde_json::from_str(&res).map_err(|e| Error::CaptchaLookup(e.to_string()))?;
+        match (res.success, res.error_codes.is_empty()) {
+            (true, _) => Ok(()),
+            (false, true) => Err(Error::CaptchaInvalid("token rejected".to_string())),
+            (false, false) => Err(Error::CaptchaInvalid(res.error_codes.join(", "))),
+        }
+    }
+}
+
+/// Sends a posted contact form to its fixed recipients.
+#[post("/forms/{name}")]
+#[tracing::instrument(skip_all)]
+pub async fn submit_form(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    name: web::Path<String>,
+    values: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let form = ses
+        .config
+        .forms
+        .get(name.as_str())
+        .ok_or_else(|| Error::NotFound(format!("form {}", name)))?;
+    let principal = form.principal(&name);
+    ses.config
+        .cors
+        .check_origin(&principal, cors::origin(request.headers()))?;
+
+    // Bots are told it went fine, so that they don't try again
+    if let Some(honeypot) = &form.honeypot
+        && values.get(honeypot).is_some_and(|value| !value.is_empty())
+    {
+        info!(form = %name, "Dropped form with the honeypot filled in");
+        return Ok(form.done());
+    }
+
+    if let Some(captcha) = &form.captcha {
+        let token = values.get(&captcha.field).map_or("", String::as_str);
+        let remote_ip = request
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string);
+        captcha
+            .verify(&captcha.secret()?, token, remote_ip.as_deref())
+            .await?;
+    }
+
+    let mail = form.message(&values)?;
+    ses.send_email_legacy(mail, principal).await?;
+    Ok(form.done())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpServer};
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn form() -> FormConfig {
+        Config::parse(
+            r#"
+            [forms.contact]
+            from = "kontakt@metaspexet.se"
+            to = ["styrelsen@metaspexet.se"]
+            origins = ["https://metaspexet.se"]
+            fields = ["name", "email", "message"]
+            subject = "Contact form: {{name}}"
+            reply_to = "email"
+            honeypot = "website"
+            "#,
+        )
+        .unwrap()
+        .forms
+        .remove("contact")
+        .unwrap()
+    }
+
+    fn values(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn builds_the_email() {
+        let form = form();
+        let mail = form
+            .message(&values(&[
+                ("name", "<b>Ada</b>\nBcc: x@evil.com"),
+                ("email", "ada@example.com"),
+                ("message", "Hej!\nMvh"),
+                ("website", ""),
+            ]))
+            .unwrap();
+
+        assert_eq!(mail.subject, "Contact form: <b>Ada</b> Bcc: x@evil.com");
+        assert_eq!(
+            mail.html.as_deref(),
+            Some(
+                "<p><strong>name</strong><br>&lt;b&gt;Ada&lt;/b&gt;<br>Bcc: x@evil.com</p>\
+                 <p><strong>email</strong><br>ada@example.com</p>\
+                 <p><strong>message</strong><br>Hej!<br>Mvh</p>"
+            )
+        );
+        assert!(matches!(
+            mail.reply_to,
+            Some(ListNameLegacy::Name(AddressFieldLegacy::Address(ref a))) if a == "ada@example.com"
+        ));
+
+        assert!(matches!(
+            form.message(&values(&[("to", "x@evil.com")])),
+            Err(Error::InvalidForm(_))
+        ));
+        assert!(matches!(
+            form.message(&values(&[("email", "a@example.com, b@example.com")])),
+            Err(Error::InvalidForm(_))
+        ));
+    }
+
+    /// Accepts the token `pass`, like a captcha provider's `siteverify`.
+    async fn mock_captcha() -> String {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/siteverify",
+                web::post().to(|params: web::Form<HashMap<String, String>>| async move {
+                    let success = params.get("secret").map(String::as_str) == Some("s3cret")
+                        && params.get("response").map(String::as_str) == Some("pass");
+                    HttpResponse::Ok().json(json!({
+                        "success": success,
+                        "error-codes": if success { vec![] } else { vec!["invalid-input-response"] },
+                    }))
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}/siteverify", address)
+    }
+
+    #[actix_web::test]
+    async fn verifies_captchas() {
+        let captcha = CaptchaConfig {
+            verify_url: mock_captcha().await,
+            secret_env: default_secret_env(),
+            field: default_captcha_field(),
+        };
+
+        assert!(
+            captcha
+                .verify("s3cret", "pass", Some("127.0.0.1"))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            captcha.verify("s3cret", "fail", None).await,
+            Err(Error::CaptchaInvalid(_))
+        ));
+        assert!(matches!(
+            captcha.verify("wrong", "pass", None).await,
+            Err(Error::CaptchaInvalid(_))
+        ));
+        assert!(matches!(
+            captcha.verify("s3cret", "", None).await,
+            Err(Error::CaptchaInvalid(_))
+        ));
+    }
+}
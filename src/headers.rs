@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use aws_sdk_sesv2::types::MessageHeader;
+
+use crate::error::Error;
+use crate::legacy::email::ListUnsubscribeLegacy;
+
+/// Headers callers may set themselves. Anything that affects routing,
+/// authentication or the MIME structure is built by spam or SES instead.
+const ALLOWED_HEADERS: &[&str] = &[
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+    "List-Id",
+    "List-Archive",
+    "List-Help",
+    "List-Owner",
+    "List-Post",
+    "List-Subscribe",
+    "X-Entity-Ref-ID",
+    "Precedence",
+    "Auto-Submitted",
+    "In-Reply-To",
+    "References",
+];
+
+/// SES rejects header values longer than this.
+const MAX_VALUE_LENGTH: usize = 870;
+
+/// Validates a single header, returning it with its canonical name.
+pub fn header(name: &str, value: &str) -> Result<MessageHeader, Error> {
+    let name = ALLOWED_HEADERS
+        .iter()
+        .find(|allowed| allowed.eq_ignore_ascii_case(name.trim()))
+        .ok_or_else(|| Error::InvalidHeader(format!("{} is not an allowed header", name)))?;
+
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(Error::InvalidHeader(format!("{} has an empty value", name)));
+    }
+    if !value.is_ascii() || value.contains(['\r', '\n']) {
+        return Err(Error::InvalidHeader(format!(
+            "{} must be a single line of ASCII",
+            name
+        )));
+    }
+    if value.len() > MAX_VALUE_LENGTH {
+        return Err(Error::InvalidHeader(format!("{} is too long", name)));
+    }
+
+    MessageHeader::builder()
+        .name(*name)
+        .value(value)
+        .build()
+        .map_err(|e| Error::InvalidHeader(format!("Failed to build header {}: {}", name, e)))
+}
+
+/// Builds the `List-Unsubscribe` and, for one-click unsubscribe (RFC 8058),
+/// `List-Unsubscribe-Post` headers.
+pub fn list_unsubscribe(unsub: &ListUnsubscribeLegacy) -> Result<Vec<MessageHeader>, Error> {
+    let mut targets = Vec::new();
+    if let Some(url) = &unsub.url {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(Error::InvalidHeader(
+                "List-Unsubscribe url must be http(s)".to_string(),
+            ));
+        }
+        targets.push(format!("<{}>", url));
+    }
+    if let Some(mailto) = &unsub.mailto {
+        let mailto = mailto.trim_start_matches("mailto:");
+        targets.push(format!("<mailto:{}>", mailto));
+    }
+    if targets.is_empty() {
+        return Err(Error::InvalidHeader(
+            "List-Unsubscribe needs a url or mailto".to_string(),
+        ));
+    }
+
+    let mut headers = vec![header("List-Unsubscribe", &targets.join(", "))?];
+    if unsub.one_click {
+        if !unsub
+            .url
+            .as_ref()
+            .is_some_and(|url| url.starts_with("https://"))
+        {
+            return Err(Error::InvalidHeader(
+                "one-click unsubscribe requires an https url".to_string(),
+            ));
+        }
+        headers.push(header(
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click",
+        )?);
+    }
+
+    Ok(headers)
+}
+
+/// Builds all custom headers for a message from the request's `headers` map
+/// and `listUnsubscribe` field.
+pub fn message_headers(
+    headers: Option<&BTreeMap<String, String>>,
+    unsub: Option<&ListUnsubscribeLegacy>,
+) -> Result<Vec<MessageHeader>, Error> {
+    let mut built = headers
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| header(name, value))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Names are canonical by now, so `X-Foo` and `x-foo` are the same here
+    for (i, h) in built.iter().enumerate() {
+        if built[..i].iter().any(|other| other.name() == h.name()) {
+            return Err(Error::InvalidHeader(format!(
+                "{} is set more than once",
+                h.name()
+            )));
+        }
+    }
+
+    if let Some(unsub) = unsub {
+        if built
+            .iter()
+            .any(|h| h.name().starts_with("List-Unsubscribe"))
+        {
+            return Err(Error::InvalidHeader(
+                "List-Unsubscribe set in both headers and listUnsubscribe".to_string(),
+            ));
+        }
+        built.extend(list_unsubscribe(unsub)?);
+    }
+
+    Ok(built)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonicalizes_name() {
+        let h = header("precedence", "bulk").unwrap();
+        assert_eq!(h.name(), "Precedence");
+        assert_eq!(h.value(), "bulk");
+    }
+
+    #[test]
+    fn rejects_unlisted_header() {
+        assert!(header("Bcc", "someone@example.com").is_err());
+        assert!(header("Content-Type", "text/plain").is_err());
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        assert!(header("X-Entity-Ref-ID", "abc\r\nBcc: someone@example.com").is_err());
+    }
+
+    #[test]
+    fn rejects_duplicates_in_any_case() {
+        let headers = BTreeMap::from([
+            ("Precedence".to_string(), "bulk".to_string()),
+            ("precedence".to_string(), "list".to_string()),
+        ]);
+        assert!(message_headers(Some(&headers), None).is_err());
+
+        let headers = BTreeMap::from([
+            ("Precedence".to_string(), "bulk".to_string()),
+            (
+                "List-Id".to_string(),
+                "<nyheter.datasektionen.se>".to_string(),
+            ),
+        ]);
+        assert_eq!(message_headers(Some(&headers), None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn one_click_unsubscribe() {
+        let unsub = ListUnsubscribeLegacy {
+            url: Some("https://datasektionen.se/unsub/123".to_string()),
+            mailto: Some("unsub@datasektionen.se".to_string()),
+            one_click: true,
+        };
+        let headers = list_unsubscribe(&unsub).unwrap();
+        assert_eq!(
+            headers[0].value(),
+            "<https://datasektionen.se/unsub/123>, <mailto:unsub@datasektionen.se>"
+        );
+        assert_eq!(headers[1].name(), "List-Unsubscribe-Post");
+        assert_eq!(headers[1].value(), "List-Unsubscribe=One-Click");
+    }
+
+    #[test]
+    fn one_click_requires_https() {
+        let unsub = ListUnsubscribeLegacy {
+            url: None,
+            mailto: Some("unsub@datasektionen.se".to_string()),
+            one_click: true,
+        };
+        assert!(list_unsubscribe(&unsub).is_err());
+    }
+}
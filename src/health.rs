@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use actix_web::{HttpResponse, get, web};
+use aws_sdk_sesv2::error::DisplayErrorContext;
+use futures_util::join;
+use tracing::warn;
+
+use crate::Client;
+use crate::hive;
+use crate::legacy::email::EmailTemplateTypeLegacy;
+use crate::metrics::METRICS;
+
+/// How long each dependency gets to answer. Nomad gives up on the whole
+/// check after 2 seconds.
+const CHECK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Failing,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn ok(detail: impl Into<String>) -> Self {
+        Self {
+            status: Status::Ok,
+            detail: detail.into(),
+        }
+    }
+
+    fn failing(detail: impl Into<String>) -> Self {
+        Self {
+            status: Status::Failing,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    /// Ready only if every check is.
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = if checks.values().all(|check| check.status == Status::Ok) {
+            Status::Ok
+        } else {
+            Status::Failing
+        };
+        Self { status, checks }
+    }
+}
+
+async fn with_timeout<E: Display>(check: impl Future<Output = Result<String, E>>) -> Check {
+    match actix_web::rt::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(detail)) => Check::ok(detail),
+        Ok(Err(e)) => Check::failing(e.to_string()),
+        Err(_) => Check::failing(format!("No answer within {:?}", CHECK_TIMEOUT)),
+    }
+}
+
+impl Client {
+    fn check_templates(&self) -> Check {
+        let missing = [
+            EmailTemplateTypeLegacy::Default,
+            EmailTemplateTypeLegacy::Metaspexet,
+        ]
+        .iter()
+        .map(|template| template.to_string())
+        .filter(|name| !self.templates.has_template(name))
+        .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            Check::ok(format!("{} templates loaded", self.templates.list().len()))
+        } else {
+            Check::failing(format!("Missing templates: {}", missing.join(", ")))
+        }
+    }
+
+    /// Checks that SES accepts our credentials, by reading the account.
+    async fn check_ses(&self) -> Result<String, String> {
+        let _timer = METRICS.time_ses("get_account");
+        let account = self
+            .inner
+            .get_account()
+            .send()
+            .await
+            .map_err(|e| DisplayErrorContext(e).to_string())?;
+
+        Ok(if account.production_access_enabled() {
+            "Credentials valid".to_string()
+        } else {
+            "Credentials valid, account is in the SES sandbox".to_string()
+        })
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let (hive, ses) = join!(
+            with_timeout(async { hive::ping().await.map(|_| "Reachable".to_string()) }),
+            with_timeout(self.check_ses()),
+        );
+
+        Readiness::new(BTreeMap::from([
+            ("templates", self.check_templates()),
+            ("hive", hive),
+            ("ses", ses),
+        ]))
+    }
+}
+
+/// Whether the server is running at all.
+#[get("/live")]
+async fn live() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": Status::Ok }))
+}
+
+/// Whether the server can send email, i.e. its templates are loaded and
+/// both Hive and SES answer. Anyone can ask, so what failed is only logged.
+#[get("/ready")]
+async fn ready(ses: web::Data<Client>) -> HttpResponse {
+    let readiness = ses.readiness().await;
+    for (name, check) in &readiness.checks {
+        if check.status == Status::Failing {
+            warn!("Readiness check {} failing: {}", name, check.detail);
+        }
+    }
+
+    let body = serde_json::json!({ "status": readiness.status });
+    match readiness.status {
+        Status::Ok => HttpResponse::Ok().json(body),
+        Status::Failing => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_only_if_every_check_is() {
+        let readiness = Readiness::new(BTreeMap::from([
+            ("templates", Check::ok("2 templates loaded")),
+            ("hive", Check::ok("Reachable")),
+        ]));
+        assert_eq!(readiness.status, Status::Ok);
+
+        let readiness = Readiness::new(BTreeMap::from([
+            ("templates", Check::ok("2 templates loaded")),
+            ("ses", Check::failing("dispatch failure")),
+        ]));
+        assert_eq!(readiness.status, Status::Failing);
+        assert_eq!(readiness.checks["ses"].status, Status::Failing);
+    }
+}
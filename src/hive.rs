@@ -0,0 +1,170 @@
+use std::env;
+
+use sha2::{Digest, Sha256};
+
+use crate::cors::BrowserKey;
+use crate::error::Error;
+use crate::metrics::METRICS;
+
+/// A stable, non-secret identifier for an API key. Used wherever spam needs
+/// to tell keys apart without storing or logging the key itself.
+pub fn key_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Makes a GET request to `path` under `HIVE_URL`, returning the response
+/// body. The path is kept out of the span since it may contain a key.
+#[tracing::instrument(name = "hive_request", skip_all)]
+async fn get(path: &str) -> Result<String, Error> {
+    let hive_url = env::var("HIVE_URL")
+        .map_err(|e| Error::EnvVarMissing(format!("HIVE_URL missing: {}", e)))?;
+
+    let secret =
+        env::var("HIVE_SECRET").map_err(|_| Error::EnvVarMissing("HIVE_SECRET".to_string()))?;
+
+    let _timer = METRICS.time_hive();
+    let client = reqwest::Client::new();
+    let res = async {
+        client
+            .get(format!("{}{}", hive_url, path))
+            .bearer_auth(secret)
+            .send()
+            .await?
+            .text()
+            .await
+    }
+    .await;
+
+    res.map_err(|e| {
+        METRICS.hive_failures.inc();
+        Error::ApiKeyLookup(e.to_string())
+    })
+}
+
+/// Checks that Hive answers at all. Any response counts, whatever its
+/// status.
+pub async fn ping() -> Result<(), Error> {
+    get("/").await.map(|_| ())
+}
+
+/// Who a request is sent on behalf of: an API key, or an official signed in
+/// with the section's identity provider, who are both looked up in Hive the
+/// same way. Or a publishable browser key or a contact form, which Hive
+/// doesn't know about and which are held to their configured senders and
+/// recipients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Key(String),
+    User(String),
+    Browser(String, BrowserKey),
+    /// A form by name, see [`crate::forms`].
+    Form(String, BrowserKey),
+}
+
+impl Principal {
+    /// Like [`key_id`], users are told apart by their username.
+    pub fn id(&self) -> String {
+        match self {
+            Principal::Key(key) => key_id(key),
+            Principal::User(username) => format!("user:{}", username),
+            Principal::Browser(key, _) => format!("browser:{}", key_id(key)),
+            Principal::Form(name, _) => format!("form:{}", name),
+        }
+    }
+
+    /// Where the principal is looked up in Hive. Keys and usernames that
+    /// aren't a plain path segment have none, so that e.g. a key ending in
+    /// `/permission/send#` can't make Hive check another permission.
+    fn path(&self) -> Option<String> {
+        match self {
+            Principal::Key(key) => segment(key).map(|key| format!("/token/{}", key)),
+            Principal::User(username) => {
+                segment(username).map(|username| format!("/user/{}", username))
+            }
+            Principal::Browser(_, _) | Principal::Form(_, _) => None,
+        }
+    }
+}
+
+/// `value` if it only has characters that are safe in a URL path segment,
+/// and isn't only dots, which clients resolve like `.` and `..`.
+fn segment(value: &str) -> Option<&str> {
+    let safe = !value.is_empty()
+        && !value.chars().all(|c| c == '.')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    Some(value).filter(|_| safe)
+}
+
+/// Checks with Hive that `principal` has been granted `permission`,
+/// returning [`Error::ApiKeyInvalid`] if it has not.
+pub async fn require_permission(principal: &Principal, permission: &str) -> Result<(), Error> {
+    let Some(path) = principal.path() else {
+        return Err(Error::ApiKeyInvalid);
+    };
+    let res = get(&format!("{}/permission/{}", path, permission)).await?;
+
+    let is_auth = res
+        .trim()
+        .parse::<bool>()
+        .map_err(|e| Error::ApiKeyLookup(format!("Key parse failed: {}", e)))?;
+
+    if !is_auth {
+        return Err(Error::ApiKeyInvalid);
+    }
+
+    Ok(())
+}
+
+/// Returns the scopes `principal` has been granted `permission` with.
+pub async fn permission_scopes(
+    principal: &Principal,
+    permission: &str,
+) -> Result<Vec<String>, Error> {
+    let Some(path) = principal.path() else {
+        return Ok(Vec::new());
+    };
+    let res = get(&format!("{}/permission/{}/scopes", path, permission)).await?;
+
+    serde_json::from_str(&res)
+        .map_err(|e| Error::ApiKeyLookup(format!("Scope parse failed: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn refuses_keys_that_are_not_a_path_segment() {
+        for key in [
+            "secret/permission/send#",
+            "secret/permission/send?",
+            "../token/secret",
+            ".",
+            "..",
+            "...",
+            "",
+        ] {
+            let principal = Principal::Key(key.to_string());
+            assert!(matches!(
+                require_permission(&principal, "admin").await,
+                Err(Error::ApiKeyInvalid)
+            ));
+            assert!(
+                permission_scopes(&principal, "send")
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+        assert_eq!(
+            Principal::Key("k3y-1_2.x~".to_string()).path().as_deref(),
+            Some("/token/k3y-1_2.x~")
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::future::Future;
+
+use actix_web::body::{self, MessageBody};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{HttpRequest, HttpResponse};
+use rusqlite::{OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::Client;
+use crate::error::Error;
+use crate::store::{self, Store};
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on responses that are replayed from an earlier request.
+const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Seconds after which a request that never finished, e.g. because spam was
+/// restarted while sending it, is given up on and may be tried again.
+const ABANDONED_AFTER: i64 = 600;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    /// Seconds the outcome of a request is kept for repeats.
+    pub retention: i64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self { retention: 86400 }
+    }
+}
+
+/// The response to the first request with an idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl From<StoredResponse> for HttpResponse {
+    fn from(stored: StoredResponse) -> Self {
+        let mut response =
+            HttpResponse::build(StatusCode::from_u16(stored.status).unwrap_or_default());
+        if let Some(content_type) = stored.content_type {
+            response.content_type(content_type);
+        }
+        response
+            .insert_header((IDEMPOTENT_REPLAYED, "true"))
+            .body(stored.body)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// The key hasn't been seen, and is now held until the request is done.
+    New,
+    /// The key was used before, for the same request.
+    Done(StoredResponse),
+}
+
+impl Store {
+    /// Holds `key` for a request by `key_id`, unless it has been used before.
+    /// Reusing a key for another request, or while the first one is still
+    /// being sent, is a conflict.
+    pub fn claim_idempotency_key(
+        &self,
+        key_id: &str,
+        key: &str,
+        request_hash: &str,
+        retention: i64,
+        now: i64,
+    ) -> Result<Claim, Error> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM idempotency_keys WHERE created_at <= ?1",
+            params![now - retention],
+        )?;
+
+        let existing = tx
+            .query_row(
+                "SELECT request_hash, status, content_type, body, created_at
+                 FROM idempotency_keys WHERE key_id = ?1 AND key = ?2",
+                params![key_id, key],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, Option<u16>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<Vec<u8>>>(3)?,
+                        row.get::<_, i64>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let claim = match existing {
+            Some((hash, _, _, _, _)) if hash != request_hash => {
+                return Err(Error::Conflict(
+                    "Idempotency-Key was already used for another request".to_string(),
+                ));
+            }
+            Some((_, Some(status), content_type, body, _)) => Claim::Done(StoredResponse {
+                status,
+                content_type,
+                body: body.unwrap_or_default(),
+            }),
+            Some((_, None, _, _, created_at)) if created_at > now - ABANDONED_AFTER => {
+                return Err(Error::Conflict(
+                    "a request with this Idempotency-Key is still being sent".to_string(),
+                ));
+            }
+            _ => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO idempotency_keys (key_id, key, request_hash, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![key_id, key, request_hash, now],
+                )?;
+                Claim::New
+            }
+        };
+        tx.commit()?;
+        Ok(claim)
+    }
+
+    pub fn finish_idempotency_key(
+        &self,
+        key_id: &str,
+        key: &str,
+        response: &StoredResponse,
+    ) -> Result<(), Error> {
+        self.conn().execute(
+            "UPDATE idempotency_keys SET status = ?3, content_type = ?4, body = ?5
+             WHERE key_id = ?1 AND key = ?2",
+            params![
+                key_id,
+                key,
+                response.status,
+                response.content_type,
+                response.body
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Lets `key` be used again, for requests that were turned away before
+    /// anything was sent and are meant to be retried.
+    pub fn release_idempotency_key(&self, key_id: &str, key: &str) -> Result<(), Error> {
+        self.conn().execute(
+            "DELETE FROM idempotency_keys WHERE key_id = ?1 AND key = ?2",
+            params![key_id, key],
+        )?;
+        Ok(())
+    }
+}
+
+/// The request's `Idempotency-Key` header, if it has one.
+fn idempotency_key(request: &HttpRequest) -> Result<Option<&str>, Error> {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => Ok(Some(key)),
+        _ => Err(Error::InvalidHeader(
+            "Idempotency-Key has to be 1 to 255 visible ASCII characters".to_string(),
+        )),
+    }
+}
+
+fn request_hash(body: &impl serde::Serialize) -> Result<String, Error> {
+    let body = serde_json::to_vec(body)
+        .map_err(|e| Error::Store(format!("Failed to hash request: {}", e)))?;
+    Ok(Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Reads the body of `response`, to store it, and puts it back.
+async fn store_response(response: HttpResponse) -> (StoredResponse, HttpResponse) {
+    let (response, body) = response.into_parts();
+    let body = body::to_bytes(body).await.unwrap_or_default();
+    let stored = StoredResponse {
+        status: response.status().as_u16(),
+        content_type: response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body: body.to_vec(),
+    };
+    (stored, response.set_body(body.boxed()))
+}
+
+impl Client {
+    /// Sends a request at most once per `Idempotency-Key` and key, returning
+    /// the first outcome again for repeats. Requests without the header are
+    /// just sent.
+    pub async fn idempotent<F>(
+        &self,
+        request: &HttpRequest,
+        key_id: &str,
+        body: &impl serde::Serialize,
+        send: F,
+    ) -> Result<HttpResponse, Error>
+    where
+        F: Future<Output = Result<HttpResponse, Error>>,
+    {
+        let Some(key) = idempotency_key(request)? else {
+            return send.await;
+        };
+
+        let retention = self.config.idempotency.retention;
+        match self.store.claim_idempotency_key(
+            key_id,
+            key,
+            &request_hash(body)?,
+            retention,
+            store::now(),
+        )? {
+            Claim::Done(stored) => return Ok(stored.into()),
+            Claim::New => {}
+        }
+
+        match send.await {
+            Ok(response) => {
+                let (stored, response) = store_response(response).await;
+                self.finish_idempotency_key(key_id, key, &stored);
+                Ok(response)
+            }
+            Err(e) if is_final(&e) => {
+                let (stored, _) = store_response(HttpResponse::from(&e)).await;
+                self.finish_idempotency_key(key_id, key, &stored);
+                Err(e)
+            }
+            Err(e) => {
+                self.store.release_idempotency_key(key_id, key)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Keeps the outcome of a request that was sent. Failing to is only
+    /// logged, since the email may well have gone out already.
+    fn finish_idempotency_key(&self, key_id: &str, key: &str, stored: &StoredResponse) {
+        if let Err(e) = self.store.finish_idempotency_key(key_id, key, stored) {
+            warn!("Failed to store outcome for Idempotency-Key: {}", e);
+        }
+    }
+}
+
+/// Whether an error is kept for repeats. Errors raised before anything was
+/// handed to SES, because spam or a service it depends on couldn't take the
+/// request right then, let the request be tried again with the same key.
+/// Errors from SES itself are kept, as SES may have sent the email anyway.
+fn is_final(e: &Error) -> bool {
+    !matches!(
+        e,
+        Error::RateLimited(_, _)
+            | Error::SendQuotaExceeded(_, _)
+            | Error::ApiKeyLookup(_)
+            | Error::IdTokenLookup(_)
+            | Error::CaptchaLookup(_)
+            | Error::TemplateLoad(_)
+            | Error::EnvVarMissing(_)
+            | Error::Config(_)
+            | Error::Store(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &str) -> StoredResponse {
+        StoredResponse {
+            status: 200,
+            content_type: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn replays_the_first_outcome() {
+        let store = Store::in_memory().unwrap();
+        let claim =
+            |key_id, key, hash, now| store.claim_idempotency_key(key_id, key, hash, 3600, now);
+
+        assert_eq!(claim("a", "retry-1", "hash", 0).unwrap(), Claim::New);
+        // Still being sent
+        assert!(matches!(
+            claim("a", "retry-1", "hash", 1),
+            Err(Error::Conflict(_))
+        ));
+
+        store
+            .finish_idempotency_key("a", "retry-1", &response("message-id"))
+            .unwrap();
+        assert_eq!(
+            claim("a", "retry-1", "hash", 2).unwrap(),
+            Claim::Done(response("message-id"))
+        );
+        assert!(matches!(
+            claim("a", "retry-1", "other hash", 2),
+            Err(Error::Conflict(_))
+        ));
+        // Keys are per API key, and forgotten after the retention window
+        assert_eq!(claim("b", "retry-1", "other hash", 2).unwrap(), Claim::New);
+        assert_eq!(
+            claim("a", "retry-1", "other hash", 3600).unwrap(),
+            Claim::New
+        );
+    }
+
+    #[test]
+    fn only_lets_go_of_requests_that_were_not_sent() {
+        assert!(is_final(&Error::InvalidAddress(String::new())));
+        assert!(is_final(&Error::SenderNotAllowed(String::new())));
+        // SES may have accepted the email before failing
+        assert!(is_final(&Error::EmailSend(String::new())));
+        assert!(!is_final(&Error::RateLimited(String::new(), 1)));
+        assert!(!is_final(&Error::SendQuotaExceeded(String::new(), 1)));
+        assert!(!is_final(&Error::ApiKeyLookup(String::new())));
+        assert!(!is_final(&Error::Store(String::new())));
+    }
+
+    #[test]
+    fn gives_up_on_abandoned_requests() {
+        let store = Store::in_memory().unwrap();
+        let claim = |now| store.claim_idempotency_key("a", "retry-1", "hash", 86400, now);
+
+        assert_eq!(claim(0).unwrap(), Claim::New);
+        assert!(claim(ABANDONED_AFTER - 1).is_err());
+        assert_eq!(claim(ABANDONED_AFTER).unwrap(), Claim::New);
+
+        store.release_idempotency_key("a", "retry-1").unwrap();
+        assert_eq!(claim(ABANDONED_AFTER + 1).unwrap(), Claim::New);
+    }
+}
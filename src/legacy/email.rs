@@ -1,10 +1,12 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Display};
 
-use base64::{Engine, prelude::BASE64_STANDARD};
-
+use crate::address::{self, Mailbox};
+use crate::delivery::Priority;
 use crate::error::Error;
+use crate::logging::Redacted;
 
-#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum EmailTemplateTypeLegacy {
     #[default]
@@ -34,60 +36,42 @@ impl From<EmailTemplateTypeLegacy> for String {
     }
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct EmailNameLegacy {
     pub name: String,
     pub address: String,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum AddressFieldLegacy {
     Address(String),
     NameAndAddress(EmailNameLegacy),
 }
 
+impl AddressFieldLegacy {
+    /// The field as the single mailbox it has to be, as for `from`.
+    pub fn mailbox(&self) -> Result<Mailbox, Error> {
+        match self {
+            AddressFieldLegacy::Address(addr) => address::parse(addr),
+            AddressFieldLegacy::NameAndAddress(name_addr) => {
+                Mailbox::new(Some(&name_addr.name), &name_addr.address)
+            }
+        }
+    }
+}
+
 impl TryFrom<&AddressFieldLegacy> for String {
     type Error = Error;
 
     fn try_from(value: &AddressFieldLegacy) -> Result<Self, Self::Error> {
         match value {
-            AddressFieldLegacy::Address(addr) => match addr.is_ascii() {
-                true => Ok(addr.clone()),
-                _ => {
-                    // if address id form Name <addr>, then we just check that addr is ASCII, rest encoded as UTF-8
-                    Ok(addr
-                        .split(",")
-                        .map(|addr| {
-                            let (name, email) = addr.split_once('<').ok_or_else(|| {
-                                Error::InvalidAddress("not in Name <addr> format".to_string())
-                            })?;
-
-                            let email = email.trim_end_matches('>');
-
-                            if !email.is_ascii() {
-                                return Err(Error::InvalidAddress(
-                                    "address is not ASCII".to_string(),
-                                ));
-                            }
-
-                            Ok(format!("{} <{}>", format_utf8(name), email))
-                        })
-                        .collect::<Result<Vec<_>, _>>()?
-                        .join(", "))
-                }
-            },
-            AddressFieldLegacy::NameAndAddress(name_addr) => {
-                let name = if name_addr.name.is_ascii() {
-                    &name_addr.name
-                } else {
-                    &format_utf8(&name_addr.name)
-                };
-                if !name_addr.address.is_ascii() {
-                    return Err(Error::NotASCII("address field".to_string()));
-                }
-                Ok(format!("{} <{}>", name, name_addr.address))
-            }
+            AddressFieldLegacy::Address(addrs) => Ok(address::parse_list(addrs)?
+                .iter()
+                .map(Mailbox::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")),
+            AddressFieldLegacy::NameAndAddress(_) => Ok(value.mailbox()?.to_string()),
         }
     }
 }
@@ -99,15 +83,11 @@ impl TryFrom<AddressFieldLegacy> for String {
     }
 }
 
-fn format_utf8(name: &str) -> String {
-    format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(name.trim()))
-}
-
 fn encoding_default() -> String {
     "base64".to_string()
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct AttachmentLegacy {
     #[serde(rename = "originalname")]
     pub original_name: String,
@@ -117,7 +97,18 @@ pub struct AttachmentLegacy {
     pub encoding: String,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+impl Debug for AttachmentLegacy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentLegacy")
+            .field("original_name", &self.original_name)
+            .field("mimetype", &self.mimetype)
+            .field("buffer", &format!("<{} bytes>", self.buffer.len()))
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ListNameLegacy {
     List(Vec<AddressFieldLegacy>),
@@ -130,7 +121,7 @@ impl TryFrom<&ListNameLegacy> for Vec<String> {
     fn try_from(value: &ListNameLegacy) -> Result<Self, Self::Error> {
         match value {
             ListNameLegacy::Name(addr) => Ok(vec![addr.try_into()?]),
-            ListNameLegacy::List(list) => list.into_iter().map(|a| a.try_into()).collect(),
+            ListNameLegacy::List(list) => list.iter().map(|a| a.try_into()).collect(),
         }
     }
 }
@@ -143,8 +134,33 @@ impl TryFrom<ListNameLegacy> for Vec<String> {
     }
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// How emails with more recipients than SES accepts in one go are split up.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchingLegacy {
+    /// One email per batch of recipients, with everyone beyond the first
+    /// batch in `bcc`.
+    #[default]
+    Bcc,
+    /// One email per recipient.
+    Individual,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ListUnsubscribeLegacy {
+    pub url: Option<String>,
+    pub mailto: Option<String>,
+    #[serde(rename = "oneClick", default)]
+    pub one_click: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct EmailRequestLegacy {
+    /// Only used by `/api/legacy/sendmail`, elsewhere the key is a bearer
+    /// token.
+    #[serde(default)]
     pub key: String,
     #[serde(default)]
     pub template: EmailTemplateTypeLegacy,
@@ -159,6 +175,26 @@ pub struct EmailRequestLegacy {
     pub bcc: Option<ListNameLegacy>,
     #[serde(rename = "attachments[]")]
     pub attachments: Option<Vec<AttachmentLegacy>>,
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(rename = "listUnsubscribe")]
+    pub list_unsubscribe: Option<ListUnsubscribeLegacy>,
+    /// Mailing list the email is sent on behalf of. Recipients who have
+    /// unsubscribed from it are skipped, and everyone else gets an individual
+    /// email with a hosted unsubscribe link.
+    pub list: Option<String>,
+    #[serde(default)]
+    pub batching: BatchingLegacy,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Extra SES message tags.
+    pub tags: Option<BTreeMap<String, String>>,
+    /// Unix time to send the email at, instead of right away. It is queued
+    /// until then.
+    #[serde(rename = "sendAt")]
+    pub send_at: Option<i64>,
+    /// Checks and renders the email, and returns it instead of sending it.
+    #[serde(rename = "dryRun", alias = "dry_run", default)]
+    pub dry_run: bool,
 }
 
 impl Debug for EmailRequestLegacy {
@@ -167,14 +203,22 @@ impl Debug for EmailRequestLegacy {
             .field("key", &"<hidden>")
             .field("template", &self.template)
             .field("from", &self.from)
-            .field("reply_to", &self.reply_to)
-            .field("to", &self.to)
-            .field("subject", &self.subject)
-            .field("content", &self.content)
-            .field("html", &self.html)
-            .field("cc", &self.cc)
-            .field("bcc", &self.bcc)
+            .field("reply_to", &Redacted(&self.reply_to))
+            .field("to", &Redacted(&self.to))
+            .field("subject", &Redacted(&self.subject))
+            .field("content", &Redacted(&self.content))
+            .field("html", &Redacted(&self.html))
+            .field("cc", &Redacted(&self.cc))
+            .field("bcc", &Redacted(&self.bcc))
             .field("attachments", &self.attachments)
+            .field("headers", &self.headers)
+            .field("list_unsubscribe", &self.list_unsubscribe)
+            .field("list", &self.list)
+            .field("batching", &self.batching)
+            .field("priority", &self.priority)
+            .field("tags", &self.tags)
+            .field("send_at", &self.send_at)
+            .field("dry_run", &self.dry_run)
             .finish()
     }
 }
@@ -196,6 +240,8 @@ mod tests {
         assert_eq!(req.subject, "Hello World");
         assert_eq!(req.html.unwrap(), "<p>Test email</p>");
         assert_eq!(req.template, EmailTemplateTypeLegacy::Default);
+        assert_eq!(req.batching, BatchingLegacy::Bcc);
+        assert_eq!(req.priority, Priority::Normal);
     }
 
     #[test]
@@ -396,4 +442,37 @@ mod tests {
             "=?UTF-8?B?w6XDpMO2?= <recipient@datasektionen.se>, =?UTF-8?B?w6XDpMO2?= <other@datasektionen.se>"
         );
     }
+
+    #[test]
+    fn valid_headers() {
+        let json = r#"{
+            "key": "mykey123",
+            "from": "sender@datasektionen.se",
+            "subject": "Newsletter",
+            "headers": {"Precedence": "bulk", "X-Entity-Ref-ID": "nl-42"},
+            "listUnsubscribe": {
+                "url": "https://datasektionen.se/unsubscribe",
+                "oneClick": true
+            }
+        }"#;
+        let req: EmailRequestLegacy = serde_json::from_str(json).unwrap();
+        assert_eq!(req.headers.unwrap().len(), 2);
+        let unsub = req.list_unsubscribe.unwrap();
+        assert!(unsub.one_click);
+        assert!(unsub.mailto.is_none());
+    }
+
+    #[test]
+    fn valid_priority_and_tags() {
+        let json = r#"{
+            "key": "mykey123",
+            "from": "sender@datasektionen.se",
+            "subject": "Newsletter",
+            "priority": "bulk",
+            "tags": {"campaign": "reccen"}
+        }"#;
+        let req: EmailRequestLegacy = serde_json::from_str(json).unwrap();
+        assert_eq!(req.priority, Priority::Bulk);
+        assert_eq!(req.tags.unwrap()["campaign"], "reccen");
+    }
 }
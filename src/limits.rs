@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::{HttpResponse, delete, get, put, web};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use rusqlite::{OptionalExtension, TransactionBehavior, params};
+
+use crate::auth::{Authorized, Sender};
+use crate::error::Error;
+use crate::hive::{self, Principal};
+use crate::store::{self, Store};
+use crate::{Client, config::Config};
+
+/// Hive permission whose scope puts a key in a limit group.
+const GROUP_PERMISSION: &str = "quota";
+
+/// How much a key may send. Unset fields fall back to the key's group and
+/// then to the defaults, and fields set to 0 mean no limit.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Limits {
+    /// Send requests per minute.
+    pub per_minute: Option<u32>,
+    /// Recipients per day (UTC).
+    pub daily: Option<u64>,
+    /// Recipients per calendar month (UTC).
+    pub monthly: Option<u64>,
+}
+
+impl Limits {
+    fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            per_minute: self.per_minute.or(fallback.per_minute),
+            daily: self.daily.or(fallback.daily),
+            monthly: self.monthly.or(fallback.monthly),
+        }
+    }
+
+    /// The limits that are enforced, leaving out those set to 0 to exempt a
+    /// key from them.
+    fn enforced(self) -> Limits {
+        Limits {
+            per_minute: self.per_minute.filter(|&limit| limit != 0),
+            daily: self.daily.filter(|&limit| limit != 0),
+            monthly: self.monthly.filter(|&limit| limit != 0),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub default: Limits,
+    /// Limits for keys whose `quota` permission in Hive is scoped to the
+    /// group's name.
+    pub groups: HashMap<String, Limits>,
+    /// Limits for single keys, by key id (see [`hive::key_id`]).
+    pub keys: HashMap<String, Limits>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            default: Limits {
+                per_minute: Some(60),
+                daily: Some(2000),
+                monthly: None,
+            },
+            groups: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn resolve(&self, key_id: &str, group: Option<&str>) -> Limits {
+        let key = self.keys.get(key_id).copied().unwrap_or_default();
+        let group = group
+            .and_then(|group| self.groups.get(group))
+            .copied()
+            .unwrap_or_default();
+        key.or(group).or(self.default)
+    }
+}
+
+/// A token bucket per key, refilled continuously at `per_minute` requests
+/// per minute.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn check(&self, key_id: &str, per_minute: u32) -> Result<(), Error> {
+        self.check_at(key_id, per_minute, Instant::now())
+    }
+
+    fn check_at(&self, key_id: &str, per_minute: u32, now: Instant) -> Result<(), Error> {
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, updated) = buckets.entry(key_id.to_string()).or_insert((capacity, now));
+
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * per_second).min(capacity);
+        *updated = now;
+
+        if *tokens < 1.0 {
+            let retry_after = ((1.0 - *tokens) / per_second).ceil() as u64;
+            return Err(Error::RateLimited(
+                format!("more than {} requests per minute", per_minute),
+                retry_after.max(1),
+            ));
+        }
+        *tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Gives back the request taken by [`RateLimiter::check`], for requests
+    /// that were turned away after all.
+    pub fn refund(&self, key_id: &str, per_minute: u32) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((tokens, _)) = buckets.get_mut(key_id) {
+            *tokens = (*tokens + 1.0).min(per_minute as f64);
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub period: String,
+    pub requests: u64,
+    pub recipients: u64,
+}
+
+fn day(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m-%d").to_string()
+}
+
+fn month(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+fn seconds_until(now: DateTime<Utc>, then: Option<NaiveDate>) -> u64 {
+    then.and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|then| (then.and_utc() - now).num_seconds().max(1) as u64)
+        .unwrap_or(1)
+}
+
+impl Store {
+    pub fn usage(&self, key_id: &str, period: &str) -> Result<Usage, Error> {
+        let usage = self
+            .conn()
+            .query_row(
+                "SELECT requests, recipients FROM usage WHERE key_id = ?1 AND period = ?2",
+                params![key_id, period],
+                |row| {
+                    Ok(Usage {
+                        period: period.to_string(),
+                        requests: row.get(0)?,
+                        recipients: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(usage.unwrap_or_else(|| Usage {
+            period: period.to_string(),
+            ..Default::default()
+        }))
+    }
+
+    /// Records a request to `recipients` recipients against the key's daily
+    /// and monthly quotas, or fails without recording anything if that would
+    /// go over either of them.
+    pub fn charge(
+        &self,
+        key_id: &str,
+        recipients: usize,
+        limits: &Limits,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let (today, this_month) = (day(now), month(now));
+        let recipients = recipients as u64;
+
+        // The check and the update are one transaction, so that concurrent
+        // requests can't each pass the check and together go over a quota
+        let mut conn = self.conn();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let used = |period: &str| -> Result<u64, Error> {
+            Ok(tx
+                .query_row(
+                    "SELECT recipients FROM usage WHERE key_id = ?1 AND period = ?2",
+                    params![key_id, period],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(0))
+        };
+
+        if let Some(daily) = limits.daily
+            && used(&today)? + recipients > daily
+        {
+            let tomorrow = now.date_naive().checked_add_days(Days::new(1));
+            return Err(Error::RateLimited(
+                format!("daily quota of {} recipients", daily),
+                seconds_until(now, tomorrow),
+            ));
+        }
+
+        if let Some(monthly) = limits.monthly
+            && used(&this_month)? + recipients > monthly
+        {
+            let next_month = now
+                .date_naive()
+                .with_day(1)
+                .and_then(|first| first.checked_add_months(Months::new(1)));
+            return Err(Error::RateLimited(
+                format!("monthly quota of {} recipients", monthly),
+                seconds_until(now, next_month),
+            ));
+        }
+
+        for period in [today, this_month] {
+            tx.execute(
+                "INSERT INTO usage (key_id, period, requests, recipients) VALUES (?1, ?2, 1, ?3)
+                 ON CONFLICT (key_id, period) DO UPDATE
+                 SET requests = requests + 1, recipients = recipients + ?3",
+                params![key_id, period, recipients],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl Store {
+    /// Limits set for a single key through the admin API, which take
+    /// precedence over the config file.
+    pub fn limit_override(&self, key_id: &str) -> Result<Option<Limits>, Error> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT per_minute, daily, monthly FROM limit_overrides WHERE key_id = ?1",
+                params![key_id],
+                |row| {
+                    Ok(Limits {
+                        per_minute: row.get(0)?,
+                        daily: row.get(1)?,
+                        monthly: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn set_limit_override(&self, key_id: &str, limits: &Limits) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT INTO limit_overrides (key_id, per_minute, daily, monthly, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (key_id) DO UPDATE
+             SET per_minute = ?2, daily = ?3, monthly = ?4, updated_at = ?5",
+            params![
+                key_id,
+                limits.per_minute,
+                limits.daily,
+                limits.monthly,
+                store::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_limit_override(&self, key_id: &str) -> Result<bool, Error> {
+        let removed = self.conn().execute(
+            "DELETE FROM limit_overrides WHERE key_id = ?1",
+            params![key_id],
+        )?;
+        Ok(removed > 0)
+    }
+}
+
+impl Client {
+    /// Looks up the limits that apply to `principal`, including its group in
+    /// Hive if any groups are configured.
+    async fn limits(&self, principal: &Principal, key_id: &str) -> Result<Limits, Error> {
+        let config: &Config = &self.config;
+        let group = if config.limits.groups.is_empty() {
+            None
+        } else {
+            hive::permission_scopes(principal, GROUP_PERMISSION)
+                .await?
+                .into_iter()
+                .find(|scope| config.limits.groups.contains_key(scope))
+        };
+
+        let overridden = self.store.limit_override(key_id)?.unwrap_or_default();
+        Ok(overridden
+            .or(config.limits.resolve(key_id, group.as_deref()))
+            .enforced())
+    }
+
+    /// Checks a send request against the rate limit and quotas of
+    /// `principal`, counting it towards them if it is allowed.
+    pub async fn enforce_limits(
+        &self,
+        principal: &Principal,
+        recipients: usize,
+    ) -> Result<(), Error> {
+        let key_id = principal.id();
+        let limits = self.limits(principal, &key_id).await?;
+
+        if let Some(per_minute) = limits.per_minute {
+            self.limiter.check(&key_id, per_minute)?;
+        }
+        self.store
+            .charge(&key_id, recipients, &limits, Utc::now())
+            .inspect_err(|_| {
+                if let Some(per_minute) = limits.per_minute {
+                    self.limiter.refund(&key_id, per_minute);
+                }
+            })
+    }
+
+    /// Counts a send towards the usage of `principal` without checking any
+    /// limits.
+    pub fn record_usage(&self, principal: &Principal, recipients: usize) -> Result<(), Error> {
+        let key_id = principal.id();
+        self.store
+            .charge(&key_id, recipients, &Limits::default(), Utc::now())
+    }
+}
+
+#[derive(serde::Serialize)]
+struct UsageResponse {
+    key: String,
+    limits: Limits,
+    today: Usage,
+    month: Usage,
+}
+
+#[get("/usage")]
+async fn get_usage(ses: web::Data<Client>, key: Authorized<Sender>) -> Result<HttpResponse, Error> {
+    let key_id = key.key_id();
+    let now = Utc::now();
+    Ok(HttpResponse::Ok().json(UsageResponse {
+        limits: ses.limits(key.principal(), key_id).await?,
+        today: ses.store.usage(key_id, &day(now))?,
+        month: ses.store.usage(key_id, &month(now))?,
+        key: key_id.to_string(),
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct OverrideResponse {
+    key: String,
+    #[serde(rename = "override")]
+    limits: Option<Limits>,
+    today: Usage,
+    month: Usage,
+}
+
+fn override_response(ses: &Client, key_id: String) -> Result<HttpResponse, Error> {
+    let now = Utc::now();
+    Ok(HttpResponse::Ok().json(OverrideResponse {
+        limits: ses.store.limit_override(&key_id)?,
+        today: ses.store.usage(&key_id, &day(now))?,
+        month: ses.store.usage(&key_id, &month(now))?,
+        key: key_id,
+    }))
+}
+
+/// The limits set for a key through the admin API, and its usage.
+#[get("/limits/{key_id}")]
+async fn get_override(
+    ses: web::Data<Client>,
+    key_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    override_response(&ses, key_id.into_inner())
+}
+
+/// Overrides the limits of a single key. Fields left out fall back to the
+/// config file.
+#[put("/limits/{key_id}")]
+async fn put_override(
+    ses: web::Data<Client>,
+    key_id: web::Path<String>,
+    limits: web::Json<Limits>,
+) -> Result<HttpResponse, Error> {
+    ses.store.set_limit_override(&key_id, &limits)?;
+    override_response(&ses, key_id.into_inner())
+}
+
+#[delete("/limits/{key_id}")]
+async fn delete_override(
+    ses: web::Data<Client>,
+    key_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if !ses.store.remove_limit_override(&key_id)? {
+        return Err(Error::NotFound(format!("limits for {}", key_id)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn resolves_key_then_group_then_default() {
+        let config = Config::parse(
+            r#"
+            [limits.default]
+            per_minute = 10
+            daily = 100
+
+            [limits.groups.bulk]
+            daily = 10000
+            monthly = 100000
+
+            [limits.keys.0123456789abcdef]
+            monthly = 5
+            "#,
+        )
+        .unwrap();
+
+        let limits = config.limits.resolve("0123456789abcdef", Some("bulk"));
+        assert_eq!(
+            limits,
+            Limits {
+                per_minute: Some(10),
+                daily: Some(10000),
+                monthly: Some(5),
+            }
+        );
+        assert_eq!(config.limits.resolve("other", None), config.limits.default);
+    }
+
+    #[test]
+    fn zero_is_no_limit() {
+        let exempt = Limits {
+            daily: Some(0),
+            ..Default::default()
+        };
+        let default = Limits {
+            per_minute: Some(10),
+            daily: Some(100),
+            monthly: None,
+        };
+        assert_eq!(
+            exempt.or(default).enforced(),
+            Limits {
+                per_minute: Some(10),
+                daily: None,
+                monthly: None,
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limits_per_key() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert!(limiter.check_at("a", 2, start).is_ok());
+        assert!(limiter.check_at("a", 2, start).is_ok());
+        assert!(limiter.check_at("b", 2, start).is_ok());
+
+        let Err(Error::RateLimited(_, retry_after)) = limiter.check_at("a", 2, start) else {
+            panic!("Expected to be rate limited");
+        };
+        assert_eq!(retry_after, 30);
+
+        assert!(
+            limiter
+                .check_at("a", 2, start + Duration::from_secs(30))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn refunds_requests_turned_away() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert!(limiter.check_at("a", 1, start).is_ok());
+        limiter.refund("a", 1);
+        assert!(limiter.check_at("a", 1, start).is_ok());
+        assert!(limiter.check_at("a", 1, start).is_err());
+
+        // Never more than a full bucket
+        limiter.refund("a", 1);
+        limiter.refund("a", 1);
+        assert!(limiter.check_at("a", 1, start).is_ok());
+        assert!(limiter.check_at("a", 1, start).is_err());
+    }
+
+    #[test]
+    fn enforces_daily_quota() {
+        let store = Store::in_memory().unwrap();
+        let limits = Limits {
+            daily: Some(100),
+            ..Default::default()
+        };
+        let now = Utc.with_ymd_and_hms(2026, 10, 18, 23, 0, 0).unwrap();
+
+        store.charge("key", 60, &limits, now).unwrap();
+        let Err(Error::RateLimited(_, retry_after)) = store.charge("key", 60, &limits, now) else {
+            panic!("Expected to be over quota");
+        };
+        assert_eq!(retry_after, 3600);
+        store.charge("key", 40, &limits, now).unwrap();
+
+        let tomorrow = now + chrono::Duration::hours(2);
+        store.charge("key", 60, &limits, tomorrow).unwrap();
+
+        let usage = store.usage("key", "2026-10").unwrap();
+        assert_eq!(usage.requests, 3);
+        assert_eq!(usage.recipients, 160);
+    }
+
+    #[test]
+    fn enforces_monthly_quota() {
+        let store = Store::in_memory().unwrap();
+        let limits = Limits {
+            monthly: Some(100),
+            ..Default::default()
+        };
+        let now = Utc.with_ymd_and_hms(2026, 12, 31, 0, 0, 0).unwrap();
+
+        store.charge("key", 100, &limits, now).unwrap();
+        let Err(Error::RateLimited(_, retry_after)) = store.charge("key", 1, &limits, now) else {
+            panic!("Expected to be over quota");
+        };
+        assert_eq!(retry_after, 24 * 3600);
+    }
+
+    #[test]
+    fn concurrent_charges_stay_within_quota() {
+        let store = Store::in_memory().unwrap();
+        let limits = Limits {
+            daily: Some(5),
+            ..Default::default()
+        };
+        let now = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+
+        let charged = std::thread::scope(|scope| {
+            let threads = (0..20)
+                .map(|_| scope.spawn(|| store.charge("key", 1, &limits, now).is_ok()))
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|&charged| charged)
+                .count()
+        });
+        assert_eq!(charged, 5);
+        assert_eq!(store.usage("key", "2026-10-18").unwrap().recipients, 5);
+    }
+
+    #[test]
+    fn stores_overrides() {
+        let store = Store::in_memory().unwrap();
+        assert_eq!(store.limit_override("key").unwrap(), None);
+
+        let limits = Limits {
+            daily: Some(50_000),
+            ..Default::default()
+        };
+        store.set_limit_override("key", &limits).unwrap();
+        assert_eq!(store.limit_override("key").unwrap(), Some(limits));
+
+        assert!(store.remove_limit_override("key").unwrap());
+        assert!(!store.remove_limit_override("key").unwrap());
+    }
+}
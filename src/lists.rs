@@ -0,0 +1,462 @@
+use std::borrow::Cow;
+
+use actix_web::{HttpResponse, delete, get, post, web};
+use rusqlite::{OptionalExtension, params};
+use serde_json::{Map, Value};
+
+use crate::Client;
+use crate::address::{self, Mailbox};
+use crate::auth::{Authorized, Sender};
+use crate::error::Error;
+use crate::legacy::email::{AddressFieldLegacy, ListNameLegacy};
+use crate::store::{Store, now};
+
+/// Recipient fields may name a managed list as `list:<id>` instead of an
+/// address.
+const LIST_PREFIX: &str = "list:";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Member {
+    pub address: String,
+    pub name: Option<String>,
+    /// Made available to the subject and content of list emails, e.g.
+    /// `{{ vars.ticket }}`.
+    #[serde(default)]
+    pub vars: Map<String, Value>,
+}
+
+impl Member {
+    /// The member as a mailbox, e.g. `Ture Teknolog <turetek@datasektionen.se>`.
+    pub fn mailbox(&self) -> Result<Mailbox, Error> {
+        Mailbox::new(self.name.as_deref(), &self.address)
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct List {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<Member>,
+}
+
+pub fn validate_id(id: &str) -> Result<(), Error> {
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(Error::List(format!(
+            "list id must be 1-64 characters of a-z, 0-9, - and _: {}",
+            id
+        )));
+    }
+    Ok(())
+}
+
+fn validate_member(member: &Member) -> Result<(), Error> {
+    member.mailbox().map(|_| ())
+}
+
+/// Removes every `list:<id>` entry from a recipient field, returning what is
+/// left of the field and the ids of the lists that were named.
+pub fn split_list_refs(field: Option<ListNameLegacy>) -> (Option<ListNameLegacy>, Vec<String>) {
+    let Some(field) = field else {
+        return (None, vec![]);
+    };
+
+    let addrs = match field {
+        ListNameLegacy::Name(addr) => vec![addr],
+        ListNameLegacy::List(addrs) => addrs,
+    };
+
+    let (refs, addrs): (Vec<_>, Vec<_>) = addrs.into_iter().partition(
+        |addr| matches!(addr, AddressFieldLegacy::Address(a) if a.trim().starts_with(LIST_PREFIX)),
+    );
+
+    let refs = refs
+        .into_iter()
+        .filter_map(|addr| match addr {
+            AddressFieldLegacy::Address(a) => a
+                .trim()
+                .strip_prefix(LIST_PREFIX)
+                .map(|id| id.trim().to_string()),
+            AddressFieldLegacy::NameAndAddress(_) => None,
+        })
+        .collect();
+
+    let field = Some(ListNameLegacy::List(addrs)).filter(|f| match f {
+        ListNameLegacy::List(addrs) => !addrs.is_empty(),
+        ListNameLegacy::Name(_) => true,
+    });
+
+    (field, refs)
+}
+
+impl Store {
+    pub fn create_list(&self, id: &str, name: &str, owner: &str) -> Result<(), Error> {
+        validate_id(id)?;
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO lists (owner, id, name, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![owner, id, name, now()],
+        )?;
+        if inserted == 0 {
+            return Err(Error::Conflict(format!("list {} already exists", id)));
+        }
+        Ok(())
+    }
+
+    /// Fails with [`Error::NotFound`] unless `owner` owns the list, so that
+    /// keys can't find out which lists other keys have.
+    fn check_owner(&self, id: &str, owner: &str) -> Result<String, Error> {
+        self.conn()
+            .query_row(
+                "SELECT name FROM lists WHERE id = ?1 AND owner = ?2",
+                params![id, owner],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| Error::NotFound(format!("list {}", id)))
+    }
+
+    pub fn list(&self, id: &str, owner: &str) -> Result<List, Error> {
+        let name = self.check_owner(id, owner)?;
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT address, name, vars FROM list_members
+             WHERE owner = ?1 AND list_id = ?2 ORDER BY address",
+        )?;
+        let members = stmt
+            .query_map(params![owner, id], |row| {
+                let vars: String = row.get(2)?;
+                Ok(Member {
+                    address: row.get(0)?,
+                    name: row.get(1)?,
+                    vars: serde_json::from_str(&vars).unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(List {
+            id: id.to_string(),
+            name,
+            members,
+        })
+    }
+
+    pub fn delete_list(&self, id: &str, owner: &str) -> Result<(), Error> {
+        self.check_owner(id, owner)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM list_members WHERE owner = ?1 AND list_id = ?2",
+            params![owner, id],
+        )?;
+        tx.execute(
+            "DELETE FROM lists WHERE owner = ?1 AND id = ?2",
+            params![owner, id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Adds members to a list, replacing the name and variables of those who
+    /// are already on it.
+    pub fn add_members(&self, id: &str, owner: &str, members: &[Member]) -> Result<(), Error> {
+        self.check_owner(id, owner)?;
+        members.iter().try_for_each(validate_member)?;
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for member in members {
+            tx.execute(
+                "INSERT INTO list_members (owner, list_id, address, name, vars, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (owner, list_id, address) DO UPDATE SET name = ?4, vars = ?5",
+                params![
+                    owner,
+                    id,
+                    member.address.trim().to_lowercase(),
+                    member.name,
+                    Value::Object(member.vars.clone()).to_string(),
+                    now()
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_members(&self, id: &str, owner: &str, addresses: &[String]) -> Result<(), Error> {
+        self.check_owner(id, owner)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for address in addresses {
+            tx.execute(
+                "DELETE FROM list_members WHERE owner = ?1 AND list_id = ?2 AND address = ?3",
+                params![owner, id, address.trim().to_lowercase()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CreateListRequest {
+    id: String,
+    name: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct AddMembersRequest {
+    members: Vec<Member>,
+}
+
+#[derive(serde::Deserialize)]
+struct RemoveMembersRequest {
+    addresses: Vec<String>,
+}
+
+#[post("/lists")]
+async fn create_list(
+    ses: web::Data<Client>,
+    key: Authorized<Sender>,
+    body: web::Json<CreateListRequest>,
+) -> Result<HttpResponse, Error> {
+    let name = body.name.as_deref().unwrap_or(&body.id);
+    ses.store.create_list(&body.id, name, key.key_id())?;
+    Ok(HttpResponse::Created().finish())
+}
+
+#[get("/lists/{id}")]
+async fn get_list(
+    ses: web::Data<Client>,
+    key: Authorized<Sender>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let list = ses.store.list(&id, key.key_id())?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[delete("/lists/{id}")]
+async fn delete_list(
+    ses: web::Data<Client>,
+    key: Authorized<Sender>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    ses.store.delete_list(&id, key.key_id())?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/lists/{id}/members")]
+async fn add_members(
+    ses: web::Data<Client>,
+    key: Authorized<Sender>,
+    id: web::Path<String>,
+    body: web::Json<AddMembersRequest>,
+) -> Result<HttpResponse, Error> {
+    ses.store.add_members(&id, key.key_id(), &body.members)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/lists/{id}/members")]
+async fn remove_members(
+    ses: web::Data<Client>,
+    key: Authorized<Sender>,
+    id: web::Path<String>,
+    body: web::Json<RemoveMembersRequest>,
+) -> Result<HttpResponse, Error> {
+    ses.store
+        .remove_members(&id, key.key_id(), &body.addresses)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Whether `text` has placeholders, which only list members have the
+/// variables for.
+pub fn has_placeholders(text: &str) -> bool {
+    let mut handlebars = handlebars::Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+        .render_template(text, &Value::Object(Map::new()))
+        .map_or(true, |rendered| rendered != text)
+}
+
+/// One recipient of an email sent individually, either given directly in
+/// `to`/`cc`/`bcc` or expanded from a managed list.
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub mailbox: String,
+    pub address: String,
+    pub vars: Option<Value>,
+}
+
+impl Recipient {
+    pub fn from_mailboxes(addrs: &str) -> Result<Vec<Self>, Error> {
+        Ok(address::parse_list(addrs)?
+            .into_iter()
+            .map(|mailbox| Recipient {
+                mailbox: mailbox.to_string(),
+                address: mailbox.address(),
+                vars: None,
+            })
+            .collect())
+    }
+
+    /// Fills in the recipient's variables in `text`, escaping them if
+    /// `text` is HTML.
+    pub fn render<'a>(&self, text: &'a str, escape: bool) -> Result<Cow<'a, str>, Error> {
+        let Some(vars) = &self.vars else {
+            return Ok(Cow::Borrowed(text));
+        };
+
+        let mut handlebars = handlebars::Handlebars::new();
+        if !escape {
+            handlebars.register_escape_fn(handlebars::no_escape);
+        }
+        Ok(Cow::Owned(handlebars.render_template(text, vars)?))
+    }
+
+    pub fn from_member(member: Member) -> Result<Self, Error> {
+        let mut vars = Map::new();
+        vars.insert("address".to_string(), Value::from(member.address.clone()));
+        vars.insert("name".to_string(), Value::from(member.name.clone()));
+        vars.insert("vars".to_string(), Value::Object(member.vars.clone()));
+
+        let mailbox = member.mailbox()?;
+        Ok(Recipient {
+            mailbox: mailbox.to_string(),
+            address: mailbox.address(),
+            vars: Some(Value::Object(vars)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(address: &str) -> Member {
+        Member {
+            address: address.to_string(),
+            name: None,
+            vars: Map::new(),
+        }
+    }
+
+    #[test]
+    fn splits_list_refs() {
+        let field: ListNameLegacy =
+            serde_json::from_str(r#"["list:nyheter", "ture@datasektionen.se"]"#).unwrap();
+        let (field, refs) = split_list_refs(Some(field));
+        assert_eq!(refs, vec!["nyheter"]);
+        let rest: Vec<String> = field.unwrap().try_into().unwrap();
+        assert_eq!(rest, vec!["ture@datasektionen.se"]);
+    }
+
+    #[test]
+    fn only_list_ref() {
+        let field: ListNameLegacy = serde_json::from_str(r#""list:nyheter""#).unwrap();
+        let (field, refs) = split_list_refs(Some(field));
+        assert_eq!(refs, vec!["nyheter"]);
+        assert!(field.is_none());
+    }
+
+    #[test]
+    fn renders_member_vars() {
+        let mut ture = member("turetek@datasektionen.se");
+        ture.name = Some("Ture <3".to_string());
+        ture.vars.insert("ticket".to_string(), Value::from(42));
+        let recipient = Recipient::from_member(ture).unwrap();
+
+        let subject = recipient.render("Hej {{ name }}, biljett {{ vars.ticket }}", false);
+        assert_eq!(subject.unwrap(), "Hej Ture <3, biljett 42");
+        let html = recipient.render("<p>Hej {{ name }}</p>", true);
+        assert_eq!(html.unwrap(), "<p>Hej Ture &lt;3</p>");
+    }
+
+    #[test]
+    fn leaves_direct_recipients_alone() {
+        let recipient = &Recipient::from_mailboxes("ture@datasektionen.se").unwrap()[0];
+        assert_eq!(recipient.render("{{ name }}", false).unwrap(), "{{ name }}");
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        assert!(has_placeholders("Hej {{ name }}"));
+        assert!(has_placeholders("Biljett {{ vars.ticket }}"));
+        assert!(has_placeholders("{{#if name}}Hej{{/if}}"));
+        assert!(!has_placeholders("Hej alla <3 & välkomna"));
+    }
+
+    #[test]
+    fn rejects_bad_ids() {
+        let store = Store::in_memory().unwrap();
+        assert!(store.create_list("Nyheter!", "Nyheter", "owner").is_err());
+        assert!(store.create_list("", "Nyheter", "owner").is_err());
+    }
+
+    #[test]
+    fn manages_members() {
+        let store = Store::in_memory().unwrap();
+        store.create_list("nyheter", "Nyheter", "owner").unwrap();
+        assert!(store.create_list("nyheter", "Nyheter", "owner").is_err());
+
+        let mut ture = member("Ture@Datasektionen.se");
+        ture.vars.insert("ticket".to_string(), Value::from(42));
+        store
+            .add_members("nyheter", "owner", &[ture, member("other@metaspexet.se")])
+            .unwrap();
+        store
+            .remove_members("nyheter", "owner", &["other@metaspexet.se".to_string()])
+            .unwrap();
+
+        let list = store.list("nyheter", "owner").unwrap();
+        assert_eq!(list.members.len(), 1);
+        assert_eq!(list.members[0].address, "ture@datasektionen.se");
+        assert_eq!(list.members[0].vars["ticket"], 42);
+    }
+
+    #[test]
+    fn hides_lists_of_other_keys() {
+        let store = Store::in_memory().unwrap();
+        store.create_list("nyheter", "Nyheter", "owner").unwrap();
+        assert!(matches!(
+            store.list("nyheter", "someone-else"),
+            Err(Error::NotFound(_))
+        ));
+        assert!(
+            store
+                .add_members("nyheter", "someone-else", &[member("a@b.se")])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn list_ids_are_per_key() {
+        let store = Store::in_memory().unwrap();
+        store.create_list("nyheter", "Nyheter", "owner").unwrap();
+        store
+            .create_list("nyheter", "Andra nyheter", "someone-else")
+            .unwrap();
+        store
+            .add_members("nyheter", "someone-else", &[member("a@datasektionen.se")])
+            .unwrap();
+
+        assert!(store.list("nyheter", "owner").unwrap().members.is_empty());
+        store.delete_list("nyheter", "someone-else").unwrap();
+        assert_eq!(store.list("nyheter", "owner").unwrap().name, "Nyheter");
+    }
+
+    #[test]
+    fn rejects_invalid_members() {
+        let store = Store::in_memory().unwrap();
+        store.create_list("nyheter", "Nyheter", "owner").unwrap();
+        assert!(
+            store
+                .add_members("nyheter", "owner", &[member("not-an-address")])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error as ActixError, HttpMessage};
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpanBuilder};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, fmt};
+
+use crate::telemetry::{self, TracerLayer};
+
+/// Response header carrying the id of the request, to match a response with
+/// its logs.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Whether to keep recipients and email bodies out of the logs.
+    pub redact: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Json,
+            redact: true,
+        }
+    }
+}
+
+/// Sets up logging to stdout, filtered by `RUST_LOG` (`info` by default).
+/// Spans also go to `tracer`, if given.
+pub fn init(config: &LoggingConfig, tracer: Option<TracerLayer>) {
+    REDACT.store(config.redact, Ordering::Relaxed);
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (json, text) = match config.format {
+        LogFormat::Json => (Some(fmt::layer().json().with_current_span(true)), None),
+        LogFormat::Text => (None, Some(fmt::layer())),
+    };
+
+    tracing_subscriber::registry()
+        .with(tracer)
+        .with(filter)
+        .with(json)
+        .with(text)
+        .init();
+}
+
+/// Whether recipients and bodies should be left out of the logs.
+pub fn redact() -> bool {
+    REDACT.load(Ordering::Relaxed)
+}
+
+/// Debug-formats as `<redacted>` while redaction is on.
+pub struct Redacted<'a, T: Debug>(pub &'a T);
+
+impl<T: Debug> Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if redact() {
+            write!(f, "<redacted>")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// The span every request is handled in. Unlike the default one it leaves
+/// out the request's path and query, which may contain keys, tokens and
+/// addresses, and only records the route.
+pub struct SpamRootSpan;
+
+impl RootSpanBuilder for SpamRootSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "default".to_string());
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .copied()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+
+        let span = tracing::info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = %route,
+            http.status_code = tracing::field::Empty,
+            request_id = %request_id,
+            exception.message = tracing::field::Empty,
+            otel.name = %format!("{} {}", request.method(), route),
+            otel.kind = "server",
+            otel.status_code = tracing::field::Empty,
+        );
+        telemetry::set_parent(&span, request);
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, ActixError>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span.clone(), outcome);
+        span.in_scope(|| tracing::info!("Finished request"));
+    }
+}
+
+/// Returns the request's id in the `X-Request-Id` header.
+pub async fn request_id_header(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, ActixError> {
+    let request_id = request.extensions().get::<RequestId>().copied();
+    let mut response = next.call(request).await?;
+
+    if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(&id.to_string()).ok()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::legacy::email::EmailRequestLegacy;
+
+    #[test]
+    fn redacts_by_default() {
+        let recipients = vec!["turetek@datasektionen.se"];
+        assert_eq!(format!("{:?}", Redacted(&recipients)), "<redacted>");
+    }
+
+    #[test]
+    fn redacts_send_requests() {
+        let mail: EmailRequestLegacy = serde_json::from_str(
+            r#"{
+                "key": "secret",
+                "from": "sender@datasektionen.se",
+                "replyTo": "reply@datasektionen.se",
+                "to": ["turetek@datasektionen.se"],
+                "subject": "Din biljett",
+                "content": "Hej!"
+            }"#,
+        )
+        .unwrap();
+        let logged = format!("{:?}", mail);
+        for hidden in ["secret", "reply@", "turetek@", "Din biljett", "Hej!"] {
+            assert!(!logged.contains(hidden), "{} in {}", hidden, logged);
+        }
+    }
+
+    #[test]
+    fn redacts_errors_about_addresses() {
+        let error = Error::InvalidAddress("x@evil.com: invalid domain".to_string());
+        assert_eq!(error.redacted(), "InvalidAddress: <redacted>");
+        let error = Error::InvalidTemplate("nope".to_string());
+        assert_eq!(error.redacted(), error.to_string());
+    }
+}
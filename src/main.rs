@@ -1,26 +1,79 @@
-use actix_cors::Cors;
-use actix_web::http::Method;
-use actix_web::middleware::Logger;
+use actix_web::middleware::from_fn;
 use actix_web::web::scope;
-use actix_web::{App, Either, HttpServer, get, post};
+use actix_web::{App, Either, HttpRequest, HttpServer, get, post};
 use actix_web::{HttpResponse, web};
+use actix_web_httpauth::middleware::HttpAuthentication;
 use aws_config::BehaviorVersion;
 use aws_sdk_sesv2 as sesv2;
 use aws_sdk_sesv2::types::builders::AttachmentBuilder;
 use aws_sdk_sesv2::types::{
     Attachment, AttachmentContentTransferEncoding, Body, Content, Destination, EmailContent,
-    Message,
+    Message, MessageHeader,
 };
 use base64::prelude::*;
-use log::{debug, error, info};
+use futures_util::{StreamExt, stream};
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::Arc;
 use std::{env, fs};
-
+use tracing::{Span, debug, error, info};
+use tracing_actix_web::TracingLogger;
+
+mod address;
+mod admin;
+mod audit;
+mod auth;
+mod compose;
+mod config;
+mod cors;
+mod delivery;
 mod error;
+mod forms;
+mod headers;
+mod health;
+mod hive;
+mod idempotency;
 mod legacy;
-
+mod limits;
+mod lists;
+mod logging;
+mod metrics;
+mod mime;
+mod oidc;
+mod queue;
+mod quota;
+mod raw;
+mod sandbox;
+mod staging;
+mod store;
+mod tags;
+mod telemetry;
+mod templates;
+mod unsubscribe;
+
+use address::Mailbox;
+use audit::AuditEntry;
+use auth::{Authorized, BrowserSender, Sender};
+use config::Config;
+use delivery::{
+    Admission, Admitted, DryRunMessage, MAX_CONCURRENT_SENDS, Pacer, Priority, SendOptions,
+    SendOutcome,
+};
 use error::Error;
-use legacy::email::{AddressFieldLegacy, EmailRequestLegacy, EmailTemplateTypeLegacy};
+use hive::Principal;
+use legacy::email::{
+    BatchingLegacy, EmailRequestLegacy, EmailTemplateTypeLegacy, ListUnsubscribeLegacy,
+};
+use limits::RateLimiter;
+use lists::Recipient;
+use logging::SpamRootSpan;
+use metrics::METRICS;
+use oidc::Oidc;
+use queue::QueuedRequest;
+use quota::SesQuota;
+use raw::RawEmailRequest;
+use store::Store;
+use templates::Templates;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum VerifiedDomains {
@@ -42,16 +95,41 @@ impl TryFrom<String> for VerifiedDomains {
     }
 }
 
+impl VerifiedDomains {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VerifiedDomains::Metaspexet => "metaspexet.se",
+            VerifiedDomains::Datasektionen => "datasektionen.se",
+            VerifiedDomains::Ddagen => "ddagen.se",
+        }
+    }
+}
+
+/// Checks that `mailbox` belongs to one of the domains verified in SES.
+fn verify_sender_domain(mailbox: &Mailbox) -> Result<VerifiedDomains, Error> {
+    VerifiedDomains::try_from(mailbox.domain().to_lowercase())
+}
+
 #[derive(serde::Serialize, Debug, Clone)]
 struct ContentData {
     is_html: bool,
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unsubscribe_url: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 struct Client {
     inner: sesv2::Client,
-    templates: handlebars::Handlebars<'static>,
+    templates: Arc<Templates>,
+    store: Arc<Store>,
+    pacer: Arc<Pacer>,
+    bulk_pacer: Arc<Pacer>,
+    quota: Arc<SesQuota>,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    /// Set if officials may sign in instead of using a key.
+    oidc: Option<Arc<Oidc>>,
 }
 
 fn load_template_file(template_name: &str) -> Result<String, std::io::Error> {
@@ -61,42 +139,111 @@ fn load_template_file(template_name: &str) -> Result<String, std::io::Error> {
 }
 
 impl Client {
-    async fn new() -> Self {
-        let config = aws_config::load_defaults(BehaviorVersion::latest())
+    async fn new(store: Store, config: Config) -> Result<Self, Error> {
+        let sdk_config = aws_config::load_defaults(BehaviorVersion::latest())
             .await
             .into_builder()
             .build();
-        let inner = sesv2::Client::new(&config);
-        let templates = handlebars::Handlebars::new();
-        Self { inner, templates }
+        let inner = sesv2::Client::new(&sdk_config);
+        let oidc = Oidc::new(&config.oidc)?.map(Arc::new);
+        Ok(Self {
+            inner,
+            templates: Arc::new(Templates::default()),
+            store: Arc::new(store),
+            pacer: Arc::new(Pacer::new()),
+            bulk_pacer: Arc::new(Pacer::new()),
+            quota: Arc::new(SesQuota::default()),
+            config: Arc::new(config),
+            limiter: Arc::new(RateLimiter::default()),
+            oidc,
+        })
     }
 
-    async fn send_email_legacy(&self, mail: EmailRequestLegacy) -> Result<String, Error> {
-        let from = match &mail.from {
-            AddressFieldLegacy::Address(addr) => addr.to_owned(),
-            AddressFieldLegacy::NameAndAddress(name_addr) => name_addr.address.to_owned(),
-        };
+    #[tracing::instrument(skip_all)]
+    async fn send_email_legacy(
+        &self,
+        mail: EmailRequestLegacy,
+        principal: Principal,
+    ) -> Result<SendOutcome, Error> {
+        let admission = Admission::Request { principal };
+        self.deliver_legacy(mail, admission).await
+    }
 
-        let domain = from
-            .trim()
-            .split('@')
-            .last()
-            .ok_or(Error::InvalidEmailDomain("missing domain".to_string()))?;
+    /// Sends `mail`, recording the attempt in the audit log.
+    async fn deliver_legacy(
+        &self,
+        mail: EmailRequestLegacy,
+        admission: Admission,
+    ) -> Result<SendOutcome, Error> {
+        let entry = AuditEntry::legacy(&mail, &admission.key_id());
+        let result = self.dispatch_legacy(mail, admission).await;
+        self.audit(entry, &result);
+        result
+    }
+
+    async fn dispatch_legacy(
+        &self,
+        mail: EmailRequestLegacy,
+        admission: Admission,
+    ) -> Result<SendOutcome, Error> {
+        let priority = mail.priority;
+        let dry_run = mail.dry_run;
+        // Scheduled emails are checked and counted like any other, then wait
+        // in the queue
+        let send_after = match admission {
+            Admission::Request { .. } => mail.send_at.filter(|at| *at > store::now()),
+            Admission::Queued { .. } => None,
+        };
+        let queueable = match admission {
+            Admission::Request { .. } if priority == Priority::Bulk || send_after.is_some() => {
+                Some(mail.clone())
+            }
+            _ => None,
+        };
 
-        match VerifiedDomains::try_from(domain.to_string()) {
-            Ok(_) => {}
-            Err(_) => {
-                return Err(Error::InvalidEmailDomain(domain.to_string()));
+        let from = mail.from.mailbox()?;
+        let domain = verify_sender_domain(&from)?;
+        admission.authorize_from(&from.address()).await?;
+        let from = from.to_string();
+
+        let (to, to_lists) = lists::split_list_refs(mail.to);
+        let (cc, cc_lists) = lists::split_list_refs(mail.cc);
+        let (bcc, bcc_lists) = lists::split_list_refs(mail.bcc);
+
+        let managed = match [to_lists, cc_lists, bcc_lists].concat().as_slice() {
+            [] => None,
+            [id] => Some(self.store.list(id, &admission.key_id())?),
+            _ => {
+                return Err(Error::List(
+                    "only one list can be sent to at a time".to_string(),
+                ));
             }
         };
 
-        // After this point, `from` is guaranteed to be a valid email address,
-        // but not assuredly ASCII
-        let from: String = mail.from.try_into()?;
+        let list = match (mail.list, &managed) {
+            (Some(list), Some(managed)) if list != managed.id => {
+                return Err(Error::List(format!(
+                    "list is set to {} but sending to list:{}",
+                    list, managed.id
+                )));
+            }
+            (list, managed) => list.or_else(|| managed.as_ref().map(|m| m.id.clone())),
+        };
+        if let Some(list) = &list {
+            lists::validate_id(list)?;
+        }
 
-        let to = mail.to.map(|addr| addr.try_into()).transpose()?;
-        let cc = mail.cc.map(|addr| addr.try_into()).transpose()?;
-        let bcc = mail.bcc.map(|addr| addr.try_into()).transpose()?;
+        let to: Option<Vec<String>> = to.map(|addr| addr.try_into()).transpose()?;
+        let cc: Option<Vec<String>> = cc.map(|addr| addr.try_into()).transpose()?;
+        let bcc: Option<Vec<String>> = bcc.map(|addr| addr.try_into()).transpose()?;
+        let addresses = [&to, &cc, &bcc]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|addrs| address::addresses(addrs))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        admission.authorize_recipients(&addresses, list.is_some())?;
 
         let content = if let Some(html) = &mail.html {
             Ok(html)
@@ -108,52 +255,6 @@ impl Client {
 
         let is_html = mail.html.is_some();
 
-        // Build the destination
-        let dest = Destination::builder()
-            .set_to_addresses(to)
-            .set_cc_addresses(cc)
-            .set_bcc_addresses(bcc)
-            .build();
-
-        // Build subject content
-        let subj = Content::builder()
-            .data(mail.subject)
-            .charset("UTF-8")
-            .build()
-            .map_err(|e| Error::EmailSend(format!("Failed to build subject content: {}", e)))?;
-
-        let body_text = if mail.template != EmailTemplateTypeLegacy::None {
-            match self.render_template(&mail.template, content.to_string(), is_html) {
-                Ok(rendered) => rendered,
-                Err(e) => {
-                    error!("Failed to render template: {}", e);
-                    content.to_string()
-                }
-            }
-        } else if !is_html {
-            let mut options = markdown::Options::default();
-            options.compile.allow_any_img_src = true;
-            options.compile.allow_dangerous_html = true;
-            markdown::to_html_with_options(content, &options).map_err(|e| {
-                Error::EmailBody(format!("Failed to convert markdown to HTML: {}", e))
-            })?
-        } else {
-            content.to_string()
-        };
-
-        // Build body content
-        let body = Body::builder()
-            .html(
-                Content::builder()
-                    .data(body_text)
-                    .charset("UTF-8")
-                    .build()
-                    .map_err(|e| {
-                        Error::EmailBody(format!("Failed to build body content: {}", e))
-                    })?,
-            )
-            .build();
-
         let attachments: Option<Vec<Attachment>> = mail
             .attachments
             .map(|atts| {
@@ -177,6 +278,8 @@ impl Client {
                             ))),
                         }?;
 
+                        METRICS.attachment_size.observe(data.len() as f64);
+
                         AttachmentBuilder::default()
                             .raw_content(data.into())
                             .file_name(att.original_name.to_owned())
@@ -194,25 +297,305 @@ impl Client {
             })
             .transpose()?;
 
-        let message = Message::builder()
-            .subject(subj)
-            .body(body)
-            .set_attachments(attachments)
-            .build();
+        let headers =
+            headers::message_headers(mail.headers.as_ref(), mail.list_unsubscribe.as_ref())?;
 
-        let email_content = EmailContent::builder().simple(message).build();
         let reply_to = mail.reply_to.map(|addr| addr.try_into()).transpose()?;
 
+        let tags = tags::message_tags(
+            &admission.key_id(),
+            &mail.template.to_string(),
+            domain.as_str(),
+            mail.tags.as_ref(),
+        )?;
+        let options = self.send_options(priority, domain, mail.template.to_string(), tags);
+
+        let message = |subject: String,
+                       body_text: String,
+                       headers: Vec<MessageHeader>|
+         -> Result<Message, Error> {
+            // Build subject content
+            let subj = Content::builder()
+                .data(subject)
+                .charset("UTF-8")
+                .build()
+                .map_err(|e| Error::EmailSend(format!("Failed to build subject content: {}", e)))?;
+
+            // Build body content
+            let body = Body::builder()
+                .html(
+                    Content::builder()
+                        .data(body_text)
+                        .charset("UTF-8")
+                        .build()
+                        .map_err(|e| {
+                            Error::EmailBody(format!("Failed to build body content: {}", e))
+                        })?,
+                )
+                .build();
+
+            Ok(Message::builder()
+                .subject(subj)
+                .body(body)
+                .set_attachments(attachments.clone())
+                .set_headers(Some(headers).filter(|h| !h.is_empty()))
+                .build())
+        };
+
+        let policy = &self.config.recipient_policy;
+        if list.is_none() && mail.batching == BatchingLegacy::Bcc {
+            let mut headers = headers;
+            headers.extend(policy.header(&addresses)?);
+            let body_text = self.render_body(&mail.template, content, is_html, None)?;
+            let message = message(mail.subject, body_text, headers)?;
+
+            let batches = policy.batches(delivery::batches(to, cc, bcc)?);
+            let messages = batches
+                .iter()
+                .map(|batch| Ok((batch.destination(), message.clone())));
+            if let Some(outcome) = self.dry_run(
+                dry_run,
+                &admission,
+                &addresses,
+                &from,
+                reply_to.as_deref(),
+                messages,
+            )? {
+                return Ok(outcome);
+            }
+
+            // Everyone was dropped by the recipient policy
+            if batches.is_empty() {
+                return SendOutcome::batched(Vec::new());
+            }
+
+            let recipients = batches.iter().map(|b| b.len()).sum();
+            let admitted = self.admit(&admission, recipients, priority).await?;
+            if admitted == Admitted::Later || send_after.is_some() {
+                let mail = queueable.expect("only bulk and scheduled requests are queued");
+                return self.enqueue(
+                    &admission,
+                    QueuedRequest::Legacy(Box::new(mail)),
+                    recipients,
+                    send_after,
+                );
+            }
+
+            if let [batch] = batches.as_slice() {
+                return self
+                    .send(&from, batch.destination(), reply_to, message, &options)
+                    .await
+                    .map(SendOutcome::Single);
+            }
+
+            let (from, reply_to, message, options) = (&from, &reply_to, &message, &options);
+            let results = stream::iter(batches)
+                .map(|batch| async move {
+                    let dest = batch.destination();
+                    let result = self
+                        .send(from, dest, reply_to.clone(), message.clone(), options)
+                        .await;
+                    (batch.addresses(), result)
+                })
+                .buffered(MAX_CONCURRENT_SENDS)
+                .collect::<Vec<_>>()
+                .await;
+
+            return SendOutcome::batched(results);
+        }
+
+        // Everyone gets their own email, with their own variables and, for
+        // list emails, their own unsubscribe link.
+        if list.is_some()
+            && headers
+                .iter()
+                .any(|h| h.name().starts_with("List-Unsubscribe"))
+        {
+            return Err(Error::InvalidHeader(
+                "List-Unsubscribe is set automatically for list emails".to_string(),
+            ));
+        }
+
+        let mut recipients = [to, cc, bcc]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|addrs| Recipient::from_mailboxes(&addrs))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        // Only members have variables, anyone else would get the subject and
+        // content with its placeholders as they are
+        if managed.is_some()
+            && !recipients.is_empty()
+            && (lists::has_placeholders(&mail.subject) || lists::has_placeholders(content))
+        {
+            return Err(Error::List(
+                "placeholders can only be used when sending to nobody but a list".to_string(),
+            ));
+        }
+        for member in managed.into_iter().flat_map(|list| list.members) {
+            recipients.push(Recipient::from_member(member)?);
+        }
+
+        let unsubscribed = match &list {
+            Some(list) => self.store.unsubscribed(&admission.key_id(), list)?,
+            None => HashSet::new(),
+        };
+        let mut seen = HashSet::new();
+        recipients.retain(|recipient| {
+            let address = recipient.address.to_lowercase();
+            !unsubscribed.contains(&address) && seen.insert(address)
+        });
+        let recipients = recipients
+            .into_iter()
+            .filter_map(|recipient| {
+                Some(Recipient {
+                    mailbox: policy.mailbox(&recipient.mailbox)?,
+                    ..recipient
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (list, key_id) = (list.as_deref(), admission.key_id());
+        let (subject, template) = (&mail.subject, &mail.template);
+        let render = |recipient: &Recipient| -> Result<(Destination, Message), Error> {
+            let unsubscribe_url = list
+                .map(|list| unsubscribe::unsubscribe_url(&key_id, list, &recipient.address))
+                .transpose()?;
+            let subject = recipient.render(subject, false)?;
+            let content = recipient.render(content, is_html)?;
+            let body_text =
+                self.render_body(template, &content, is_html, unsubscribe_url.as_deref())?;
+
+            let mut headers = headers.clone();
+            headers.extend(policy.header(std::slice::from_ref(&recipient.address))?);
+            if let Some(url) = unsubscribe_url {
+                headers.extend(headers::list_unsubscribe(&ListUnsubscribeLegacy {
+                    url: Some(url),
+                    mailto: None,
+                    one_click: true,
+                })?);
+            }
+
+            let dest = Destination::builder()
+                .to_addresses(recipient.mailbox.to_owned())
+                .build();
+            Ok((dest, message(subject.into_owned(), body_text, headers)?))
+        };
+
+        let addresses = recipients
+            .iter()
+            .map(|recipient| recipient.address.clone())
+            .collect::<Vec<_>>();
+        if let Some(outcome) = self.dry_run(
+            dry_run,
+            &admission,
+            &addresses,
+            &from,
+            reply_to.as_deref(),
+            recipients.iter().map(render),
+        )? {
+            return Ok(outcome);
+        }
+
+        let admitted = self.admit(&admission, recipients.len(), priority).await?;
+        if admitted == Admitted::Later || send_after.is_some() {
+            let mail = queueable.expect("only bulk and scheduled requests are queued");
+            return self.enqueue(
+                &admission,
+                QueuedRequest::Legacy(Box::new(mail)),
+                recipients.len(),
+                send_after,
+            );
+        }
+
+        let (from, reply_to, options, render) = (&from, &reply_to, &options, &render);
+        let results = stream::iter(recipients)
+            .map(|recipient| async move {
+                let result = match render(&recipient) {
+                    Ok((dest, message)) => {
+                        self.send(from, dest, reply_to.clone(), message, options)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                (vec![recipient.address], result)
+            })
+            .buffered(MAX_CONCURRENT_SENDS)
+            .collect::<Vec<_>>()
+            .await;
+
+        SendOutcome::batched(results)
+    }
+
+    /// The messages a request would send, instead of sending them, if it
+    /// asked for a dry run or its key is in the sandbox (see
+    /// [`crate::sandbox`]). `messages` are only built then.
+    fn dry_run(
+        &self,
+        requested: bool,
+        admission: &Admission,
+        addresses: &[String],
+        from: &str,
+        reply_to: Option<&[String]>,
+        messages: impl Iterator<Item = Result<(Destination, Message), Error>>,
+    ) -> Result<Option<SendOutcome>, Error> {
+        if !requested
+            && !self
+                .config
+                .sandbox
+                .is_dry_run(&admission.key_id(), addresses)
+        {
+            return Ok(None);
+        }
+
+        let messages = messages
+            .map(|message| {
+                let (dest, message) = message?;
+                Ok(DryRunMessage::new(from, &dest, reply_to, &message))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Some(SendOutcome::DryRun(messages)))
+    }
+
+    #[tracing::instrument(name = "send_email", skip_all, fields(recipients))]
+    async fn send(
+        &self,
+        from: &str,
+        dest: Destination,
+        reply_to: Option<Vec<String>>,
+        message: Message,
+        options: &SendOptions,
+    ) -> Result<String, Error> {
+        let recipients =
+            dest.to_addresses().len() + dest.cc_addresses().len() + dest.bcc_addresses().len();
+        Span::current().record("recipients", recipients);
+        self.pace(recipients, options.priority).await;
+
+        let email_content = EmailContent::builder().simple(message).build();
+
+        let timer = METRICS.time_ses("send_email");
         let resp = self
             .inner
             .send_email()
             .from_email_address(from)
             .destination(dest)
             .set_reply_to_addresses(reply_to)
+            .set_configuration_set_name(options.configuration_set.clone())
+            .set_email_tags(Some(options.tags.clone()).filter(|t| !t.is_empty()))
             .content(email_content)
             .send()
             .await
-            .map_err(|e| Error::EmailSend(format!("Email failed to send: {}", e)))?;
+            .map_err(|e| Error::EmailSend(format!("Email failed to send: {}", e)));
+        drop(timer);
+
+        METRICS.record_send(
+            options.domain.as_str(),
+            &options.template,
+            recipients,
+            &resp,
+        );
+        let resp = resp?;
 
         // The response includes a message ID (if accepted)
         let message_id = resp.message_id().map(|s| s.to_string()).unwrap_or_default();
@@ -220,23 +603,59 @@ impl Client {
         Ok(message_id)
     }
 
-    fn load_templates(&mut self) -> Result<(), Error> {
+    fn render_body(
+        &self,
+        template: &EmailTemplateTypeLegacy,
+        content: &str,
+        is_html: bool,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<String, Error> {
+        if *template != EmailTemplateTypeLegacy::None {
+            match self.render_template(template, content.to_string(), is_html, unsubscribe_url) {
+                Ok(rendered) => Ok(rendered),
+                Err(e) => {
+                    error!("Failed to render template: {}", e);
+                    Ok(content.to_string())
+                }
+            }
+        } else if !is_html {
+            let mut options = markdown::Options::default();
+            options.compile.allow_any_img_src = true;
+            options.compile.allow_dangerous_html = true;
+            markdown::to_html_with_options(content, &options)
+                .map_err(|e| Error::EmailBody(format!("Failed to convert markdown to HTML: {}", e)))
+        } else {
+            Ok(content.to_string())
+        }
+    }
+
+    fn load_templates(&self) -> Result<(), Error> {
         let template_files = vec![
             (EmailTemplateTypeLegacy::Default, "default/html.hbs"),
             (EmailTemplateTypeLegacy::Metaspexet, "metaspexet/html.hbs"),
         ];
 
-        for (template_type, file_name) in template_files {
+        let page_files = vec![
+            ("unsubscribe/confirm", "unsubscribe/confirm.hbs"),
+            ("unsubscribe/done", "unsubscribe/done.hbs"),
+            ("compose/page", "compose/page.hbs"),
+            ("compose/callback", "compose/callback.hbs"),
+        ];
+
+        let template_files = template_files
+            .into_iter()
+            .map(|(template_type, file_name)| (template_type.to_string(), file_name))
+            .chain(
+                page_files
+                    .into_iter()
+                    .map(|(name, file_name)| (name.to_string(), file_name)),
+            );
+
+        for (template_name, file_name) in template_files {
             match load_template_file(file_name) {
                 Ok(template_content) => {
                     self.templates
-                        .register_template_string(&template_type.to_string(), template_content)
-                        .map_err(|e| {
-                            Error::TemplateLoad(format!(
-                                "Failed to register template {}: {}",
-                                file_name, e
-                            ))
-                        })?;
+                        .register_file(&template_name, file_name, template_content)?;
                 }
                 Err(e) => {
                     return Err(Error::TemplateLoad(format!(
@@ -247,14 +666,16 @@ impl Client {
             };
         }
 
-        Ok(())
+        self.templates.load_replacements(&self.store)
     }
 
+    #[tracing::instrument(skip_all)]
     fn render_template(
         &self,
         template: &EmailTemplateTypeLegacy,
         content: String,
         is_html: bool,
+        unsubscribe_url: Option<&str>,
     ) -> Result<String, Error> {
         let content = if is_html {
             content
@@ -266,16 +687,26 @@ impl Client {
                 Error::EmailBody(format!("Failed to convert markdown to HTML: {}", e))
             })?
         };
-        let data = ContentData { is_html, content };
+        let data = ContentData {
+            is_html,
+            content,
+            unsubscribe_url: unsubscribe_url.map(str::to_string),
+        };
         let rendered = self.templates.render(&template.to_string(), &data)?;
-        debug!("Rendered template: {}", rendered);
+        if !logging::redact() {
+            debug!("Rendered template: {}", rendered);
+        }
         Ok(rendered)
     }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    let config = Config::load().map_err(|e| std::io::Error::other(e.to_string()))?;
+    let (telemetry, tracer) = telemetry::init(&config.tracing)
+        .map_err(|e| std::io::Error::other(e.to_string()))?
+        .unzip();
+    logging::init(&config.logging, tracer);
 
     let address = env::var("HOST_ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = env::var("PORT")
@@ -283,36 +714,80 @@ async fn main() -> std::io::Result<()> {
         .parse::<u16>()
         .unwrap_or(8000);
 
-    let mut client = Client::new().await;
+    let database_path = env::var("DATABASE_PATH").unwrap_or_else(|_| "spam.db".to_string());
+    let store = Store::open(&database_path).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let client = Client::new(store, config)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
     client
         .load_templates()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
     let client = web::Data::new(client);
+    quota::spawn_refresh(client.clone());
+    queue::spawn_worker(client.clone());
 
     info!("Listening on {}:{}", address, port);
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_header()
-            .allowed_methods([Method::POST])
-            .allow_any_origin();
+    let result = HttpServer::new(move || {
         App::new()
-            .wrap(cors)
-            .wrap(Logger::default())
+            .wrap(
+                client
+                    .config
+                    .cors
+                    .middleware(client.config.forms.values().flat_map(|form| &form.origins)),
+            )
+            .wrap(from_fn(logging::request_id_header))
+            .wrap(TracingLogger::<SpamRootSpan>::new())
             .app_data(client.clone())
+            .service(metrics::get_metrics)
+            .service(unsubscribe::unsubscribe_page)
+            .service(unsubscribe::unsubscribe)
+            .service(compose::routes())
             .service(
                 scope("/api")
                     .service(ping)
-                    .service(scope("/legacy").service(send_mail_legacy)),
+                    .service(
+                        scope("/health")
+                            .service(health::live)
+                            .service(health::ready),
+                    )
+                    .service(scope("/legacy").service(send_mail_legacy))
+                    .service(admin::routes())
+                    // Browser keys can only be used to send. Send endpoints
+                    // authorize through their `Authorized` argument, which
+                    // audits rejected keys
+                    .service(send_mail)
+                    .service(send_raw)
+                    // Forms are held to their own recipients, and need no key
+                    .service(forms::submit_form)
+                    // Everything else needs a key with the `send` permission
+                    .service(
+                        scope("")
+                            .wrap(HttpAuthentication::bearer(auth::validator::<Sender>))
+                            .service(limits::get_usage)
+                            .service(lists::create_list)
+                            .service(lists::get_list)
+                            .service(lists::delete_list)
+                            .service(lists::add_members)
+                            .service(lists::remove_members),
+                    ),
             )
     })
     .bind((address, port))?
     .run()
-    .await
+    .await;
+
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    result
 }
 
 #[post("/sendmail")]
+#[tracing::instrument(skip_all)]
 async fn send_mail_legacy(
     ses: web::Data<Client>,
+    request: HttpRequest,
     body: Either<web::Json<EmailRequestLegacy>, web::Form<EmailRequestLegacy>>,
 ) -> Result<HttpResponse, Error> {
     let body = match body {
@@ -320,36 +795,65 @@ async fn send_mail_legacy(
         Either::Right(form) => form.into_inner(),
     };
 
-    debug!("received email request: {:?}", body);
+    debug!(request = ?body, "Received email request");
 
-    let hive_url = env::var("HIVE_URL")
-        .map_err(|e| Error::EnvVarMissing(format!("HIVE_URL missing: {}", e)))?;
+    let sender = async {
+        let sender = Authorized::<Sender>::check(Principal::Key(body.key.clone())).await?;
+        ses.config
+            .cors
+            .check_origin(sender.principal(), cors::origin(request.headers()))?;
+        Ok::<_, Error>(sender)
+    }
+    .await
+    .inspect_err(|e| ses.audit_rejected(&hive::key_id(&body.key), e))?;
 
-    let client = reqwest::Client::new();
-    let res = client
-        .get(format!("{}/token/{}/permission/send", hive_url, &body.key))
-        .bearer_auth(
-            env::var("HIVE_SECRET").map_err(|_| Error::EnvVarMissing("HIVE_SECRET".to_string()))?,
-        )
-        .send()
-        .await
-        .map_err(|e| Error::ApiKeyLookup(e.to_string()))?
-        .text()
-        .await
-        .map_err(|e| Error::ApiKeyLookup(e.to_string()))?;
+    let send = ses.send_email_legacy(body.clone(), sender.principal().clone());
+    ses.idempotent(&request, sender.key_id(), &body, async {
+        send.await.map(HttpResponse::from)
+    })
+    .await
+}
 
-    let is_auth = res
-        .trim()
-        .parse::<bool>()
-        .map_err(|e| Error::ApiKeyLookup(format!("Key parse failed: {}", e)))?;
+/// Like `/api/legacy/sendmail`, but authorized by the bearer token instead of
+/// a key in the body, so that officials can send signed in.
+#[post("/send")]
+#[tracing::instrument(skip_all)]
+async fn send_mail(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    sender: Authorized<BrowserSender>,
+    body: Either<web::Json<EmailRequestLegacy>, web::Form<EmailRequestLegacy>>,
+) -> Result<HttpResponse, Error> {
+    let body = match body {
+        Either::Left(json) => json.into_inner(),
+        Either::Right(form) => form.into_inner(),
+    };
 
-    if !is_auth {
-        return Err(Error::ApiKeyInvalid);
-    }
+    debug!(request = ?body, "Received email request");
 
-    ses.send_email_legacy(body)
-        .await
-        .map(|message_id| HttpResponse::Ok().body(format!("{}", message_id)))
+    let send = ses.send_email_legacy(body.clone(), sender.principal().clone());
+    ses.idempotent(&request, sender.key_id(), &body, async {
+        send.await.map(HttpResponse::from)
+    })
+    .await
+}
+
+#[post("/sendraw")]
+async fn send_raw(
+    ses: web::Data<Client>,
+    request: HttpRequest,
+    key: Authorized<Sender>,
+    body: web::Json<RawEmailRequest>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+
+    debug!(request = ?body, "Received raw email request");
+
+    let send = ses.send_raw_email(body.clone(), key.principal().clone());
+    ses.idempotent(&request, key.key_id(), &body, async {
+        send.await.map(HttpResponse::from)
+    })
+    .await
 }
 
 #[get("/ping")]
@@ -0,0 +1,187 @@
+use std::env;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::{HttpResponse, get, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use tracing::warn;
+
+use crate::Client;
+use crate::error::Error;
+
+/// Every metric spam exposes on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// `SendEmail` calls, by sender domain, template and result.
+    pub sends: IntCounterVec,
+    /// Recipients of `SendEmail` calls, by sender domain, template and result.
+    pub recipients: IntCounterVec,
+    /// Errors returned to clients, by `Error` variant.
+    pub errors: IntCounterVec,
+    pub hive_duration: Histogram,
+    pub hive_failures: IntCounter,
+    /// Calls to SES, by operation.
+    pub ses_duration: HistogramVec,
+    pub attachment_size: Histogram,
+    pub queue_depth: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("spam".to_string()), None)?;
+
+        let sends = IntCounterVec::new(
+            Opts::new("sends_total", "SendEmail calls"),
+            &["domain", "template", "result"],
+        )?;
+        let recipients = IntCounterVec::new(
+            Opts::new("recipients_total", "Recipients of SendEmail calls"),
+            &["domain", "template", "result"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Errors returned to clients"),
+            &["error"],
+        )?;
+        let hive_duration = Histogram::with_opts(HistogramOpts::new(
+            "hive_request_duration_seconds",
+            "Time taken by requests to Hive",
+        ))?;
+        let hive_failures = IntCounter::new("hive_failures_total", "Failed requests to Hive")?;
+        let ses_duration = HistogramVec::new(
+            HistogramOpts::new("ses_request_duration_seconds", "Time taken by calls to SES"),
+            &["operation"],
+        )?;
+        let attachment_size = Histogram::with_opts(
+            HistogramOpts::new("attachment_size_bytes", "Size of attachments")
+                .buckets(prometheus::exponential_buckets(1024.0, 4.0, 10)?),
+        )?;
+        let queue_depth = IntGauge::new("queue_depth", "Emails waiting in the queue")?;
+
+        registry.register(Box::new(sends.clone()))?;
+        registry.register(Box::new(recipients.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(hive_duration.clone()))?;
+        registry.register(Box::new(hive_failures.clone()))?;
+        registry.register(Box::new(ses_duration.clone()))?;
+        registry.register(Box::new(attachment_size.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+
+        Ok(Self {
+            registry,
+            sends,
+            recipients,
+            errors,
+            hive_duration,
+            hive_failures,
+            ses_duration,
+            attachment_size,
+            queue_depth,
+        })
+    }
+
+    /// Counts a `SendEmail` call to `recipients` recipients.
+    pub fn record_send<T>(
+        &self,
+        domain: &str,
+        template: &str,
+        recipients: usize,
+        result: &Result<T, Error>,
+    ) {
+        let result = if result.is_ok() { "ok" } else { "error" };
+        self.sends
+            .with_label_values(&[domain, template, result])
+            .inc();
+        self.recipients
+            .with_label_values(&[domain, template, result])
+            .inc_by(recipients as u64);
+    }
+
+    /// Starts timing a call to SES. The time is recorded when the returned
+    /// guard is dropped.
+    pub fn time_ses(&self, operation: &str) -> Timer {
+        Timer {
+            histogram: self.ses_duration.with_label_values(&[operation]),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn time_hive(&self) -> Timer {
+        Timer {
+            histogram: self.hive_duration.clone(),
+            start: Instant::now(),
+        }
+    }
+}
+
+pub struct Timer {
+    histogram: Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+/// Exposes every metric. If `METRICS_TOKEN` is set, scrapers have to send it
+/// as a bearer token.
+#[get("/metrics")]
+async fn get_metrics(ses: web::Data<Client>, auth: Option<BearerAuth>) -> HttpResponse {
+    if let Ok(token) = env::var("METRICS_TOKEN")
+        && auth.is_none_or(|auth| auth.token() != token)
+    {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match ses.store.queue_depth() {
+        Ok(depth) => METRICS.queue_depth.set(depth as i64),
+        Err(e) => warn!("Failed to get queue depth: {}", e),
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&METRICS.registry.gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_sends() {
+        let metrics = Metrics::new().unwrap();
+        metrics.record_send("datasektionen.se", "default", 3, &Ok(()));
+        metrics.record_send::<()>(
+            "datasektionen.se",
+            "default",
+            2,
+            &Err(Error::MissingContent),
+        );
+
+        let ok = ["datasektionen.se", "default", "ok"];
+        assert_eq!(metrics.sends.with_label_values(&ok).get(), 1);
+        assert_eq!(metrics.recipients.with_label_values(&ok).get(), 3);
+
+        let mut body = Vec::new();
+        TextEncoder::new()
+            .encode(&metrics.registry.gather(), &mut body)
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(
+            "spam_recipients_total{domain=\"datasektionen.se\",result=\"error\",template=\"default\"} 2"
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use aws_sdk_sesv2::types::{Destination, Message};
+use base64::prelude::*;
+
+/// Recommended longest header line, without the CRLF.
+const MAX_LINE_LENGTH: usize = 78;
+/// Bytes of text in an encoded word, which `=?UTF-8?B?` and `?=` leave 63
+/// characters of base64 for.
+const MAX_ENCODED_BYTES: usize = 45;
+
+/// Renders `message` as the MIME message SES would make of it, for dry runs.
+/// SES adds its own `Message-ID` and `Date` on top.
+pub fn render(
+    from: &str,
+    dest: &Destination,
+    reply_to: Option<&[String]>,
+    message: &Message,
+) -> String {
+    let mut mime = String::new();
+    let mut header =
+        |name: &str, value: &str| mime.push_str(&fold(&format!("{}: {}", name, value)));
+
+    header("From", from);
+    if !dest.to_addresses().is_empty() {
+        header("To", &dest.to_addresses().join(", "));
+    }
+    if !dest.cc_addresses().is_empty() {
+        header("Cc", &dest.cc_addresses().join(", "));
+    }
+    if let Some(reply_to) = reply_to.filter(|reply_to| !reply_to.is_empty()) {
+        header("Reply-To", &reply_to.join(", "));
+    }
+    let subject = message
+        .subject()
+        .map(|subject| subject.data())
+        .unwrap_or_default();
+    header("Subject", &encode_words(subject));
+    for custom in message.headers() {
+        header(custom.name(), custom.value());
+    }
+    header("MIME-Version", "1.0");
+
+    let (content_type, body) = match message.body() {
+        Some(body) if body.html().is_some() => ("text/html", body.html()),
+        Some(body) => ("text/plain", body.text()),
+        None => ("text/plain", None),
+    };
+    let body = body.map(|content| content.data()).unwrap_or_default();
+    let body_part = format!(
+        "Content-Type: {}; charset=UTF-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+        content_type,
+        base64_lines(body.as_bytes())
+    );
+
+    if message.attachments().is_empty() {
+        mime.push_str(&body_part);
+        return mime;
+    }
+
+    let boundary = format!("spam-{:016x}", rand::random::<u64>());
+    mime.push_str(&format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n--{}\r\n{}",
+        boundary, boundary, body_part
+    ));
+    for attachment in message.attachments() {
+        mime.push_str(&format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+            boundary,
+            attachment
+                .content_type()
+                .unwrap_or("application/octet-stream"),
+            attachment.file_name().replace(['"', '\\'], "_"),
+            base64_lines(attachment.raw_content().as_ref())
+        ));
+    }
+    mime.push_str(&format!("--{}--\r\n", boundary));
+    mime
+}
+
+/// RFC 2047 encoded words for header text that isn't ASCII. Long text is
+/// split into several words, as each may be at most 75 characters, without
+/// splitting any character between them.
+pub fn encode_words(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in text.chars() {
+        if chunk.len() + c.len_utf8() > MAX_ENCODED_BYTES {
+            words.push(format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(&chunk)));
+    words.join(" ")
+}
+
+/// Folds a header line at spaces, so that lines stay within 78 characters
+/// where they can.
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut rest = line;
+    while rest.len() > MAX_LINE_LENGTH
+        && let Some(at) = rest.as_bytes()[..=MAX_LINE_LENGTH]
+            .iter()
+            .rposition(|&b| b == b' ')
+            .filter(|&at| at > 0)
+    {
+        folded.push_str(&rest[..at]);
+        folded.push_str("\r\n");
+        rest = &rest[at..];
+    }
+    folded.push_str(rest);
+    folded.push_str("\r\n");
+    folded
+}
+
+/// Base64 in lines of 76 characters, as MIME wants it.
+fn base64_lines(data: &[u8]) -> String {
+    BASE64_STANDARD
+        .encode(data)
+        .as_bytes()
+        .chunks(76)
+        .map(|line| format!("{}\r\n", String::from_utf8_lossy(line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_sesv2::types::{Attachment, Body, Content, MessageHeader};
+
+    use super::*;
+
+    fn content(data: &str) -> Content {
+        Content::builder().data(data).build().unwrap()
+    }
+
+    #[test]
+    fn renders_what_ses_would_send() {
+        let dest = Destination::builder()
+            .to_addresses("a@datasektionen.se")
+            .bcc_addresses("hidden@datasektionen.se")
+            .build();
+        let message = || {
+            Message::builder()
+                .subject(content("Hej då"))
+                .body(Body::builder().html(content("<p>Hej</p>")).build())
+                .headers(
+                    MessageHeader::builder()
+                        .name("X-Test")
+                        .value("1")
+                        .build()
+                        .unwrap(),
+                )
+        };
+
+        assert_eq!(
+            render("d-sys@datasektionen.se", &dest, None, &message().build()),
+            "From: d-sys@datasektionen.se\r\n\
+             To: a@datasektionen.se\r\n\
+             Subject: =?UTF-8?B?SGVqIGTDpQ==?=\r\n\
+             X-Test: 1\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/html; charset=UTF-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             PHA+SGVqPC9wPg==\r\n"
+        );
+
+        let message = message()
+            .attachments(
+                Attachment::builder()
+                    .raw_content(b"hello".to_vec().into())
+                    .file_name("hello.txt")
+                    .content_type("text/plain")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let mime = render("d-sys@datasektionen.se", &dest, None, &message);
+        let parsed = mail_parser::MessageParser::default()
+            .parse(mime.as_bytes())
+            .unwrap();
+        assert_eq!(parsed.subject(), Some("Hej då"));
+        assert_eq!(parsed.body_html(0).as_deref(), Some("<p>Hej</p>"));
+        assert_eq!(parsed.attachment(0).unwrap().contents(), b"hello");
+        assert!(!mime.contains("hidden@datasektionen.se"));
+    }
+
+    #[test]
+    fn folds_long_headers() {
+        let subject = "Välkommen till mottagningen! Här är schemat för nollningens första vecka";
+        let dest = Destination::builder()
+            .to_addresses("a@datasektionen.se")
+            .build();
+        let message = Message::builder()
+            .subject(content(subject))
+            .body(Body::builder().text(content("Hej")).build())
+            .build();
+
+        let mime = render("d-sys@datasektionen.se", &dest, None, &message);
+        assert!(mime.lines().all(|line| line.len() <= MAX_LINE_LENGTH));
+        let parsed = mail_parser::MessageParser::default()
+            .parse(mime.as_bytes())
+            .unwrap();
+        assert_eq!(parsed.subject(), Some(subject));
+    }
+}
@@ -0,0 +1,384 @@
+use std::time::Duration;
+
+use actix_web::{HttpResponse, get, web};
+use rusqlite::params;
+use tracing::{info, warn};
+
+use crate::Client;
+use crate::delivery::{Admission, Priority, SendOutcome};
+use crate::error::Error;
+use crate::legacy::email::EmailRequestLegacy;
+use crate::raw::RawEmailRequest;
+use crate::store::{self, Store};
+
+/// How often the queue is checked for emails that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many emails are taken from the queue at a time.
+const POLL_BATCH: usize = 10;
+
+/// How many times an email is tried before it is given up on, for emails
+/// that keep not fitting in the quota.
+const MAX_ATTEMPTS: u32 = 48;
+
+/// How many emails are listed per page unless asked for fewer.
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// A send request as it is kept in the queue. The key is never stored, the
+/// request is sent on behalf of the key id it was queued with.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum QueuedRequest {
+    Legacy(Box<EmailRequestLegacy>),
+    Raw(RawEmailRequest),
+}
+
+impl QueuedRequest {
+    fn priority(&self) -> Priority {
+        match self {
+            QueuedRequest::Legacy(mail) => mail.priority,
+            QueuedRequest::Raw(mail) => mail.priority,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueStatus {
+    Queued,
+    Sent,
+    Failed,
+}
+
+impl QueueStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QueueStatus::Queued => "queued",
+            QueueStatus::Sent => "sent",
+            QueueStatus::Failed => "failed",
+        }
+    }
+}
+
+/// An email in the queue as admins see it, without its contents.
+#[derive(serde::Serialize, Debug)]
+pub struct QueueEntry {
+    pub id: i64,
+    pub key_id: String,
+    pub priority: String,
+    pub recipients: usize,
+    pub status: String,
+    pub attempts: u32,
+    pub send_after: i64,
+    pub result: Option<String>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct QueueFilter {
+    pub status: Option<QueueStatus>,
+    /// Only emails with a lower id, to get the next page.
+    pub before: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug)]
+struct QueuedEmail {
+    id: i64,
+    key_id: String,
+    request: String,
+    /// Including this one.
+    attempts: u32,
+}
+
+impl Store {
+    pub fn enqueue(
+        &self,
+        key_id: &str,
+        request: &QueuedRequest,
+        recipients: usize,
+        send_after: i64,
+    ) -> Result<i64, Error> {
+        let json = serde_json::to_string(request)
+            .map_err(|e| Error::Store(format!("Failed to serialize request: {}", e)))?;
+
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO queue
+                (key_id, priority, request, recipients, status, attempts, send_after, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7)",
+            params![
+                key_id,
+                request.priority().as_str(),
+                json,
+                recipients as i64,
+                QueueStatus::Queued.as_str(),
+                send_after,
+                store::now(),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Number of emails waiting to be sent.
+    pub fn queue_depth(&self) -> Result<u64, Error> {
+        Ok(self.conn().query_row(
+            "SELECT COUNT(*) FROM queue WHERE status = ?1",
+            params![QueueStatus::Queued.as_str()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Emails in the queue, newest first.
+    pub fn queue_entries(&self, filter: &QueueFilter) -> Result<Vec<QueueEntry>, Error> {
+        let conn = self.conn();
+        let entries = conn
+            .prepare(
+                "SELECT id, key_id, priority, recipients, status, attempts, send_after, result,
+                        created_at, finished_at
+                 FROM queue
+                 WHERE (?1 IS NULL OR status = ?1) AND (?2 IS NULL OR id < ?2)
+                 ORDER BY id DESC LIMIT ?3",
+            )?
+            .query_map(
+                params![
+                    filter.status.map(|status| status.as_str()),
+                    filter.before,
+                    filter
+                        .limit
+                        .unwrap_or(DEFAULT_PAGE_SIZE)
+                        .clamp(1, MAX_PAGE_SIZE) as i64,
+                ],
+                |row| {
+                    Ok(QueueEntry {
+                        id: row.get(0)?,
+                        key_id: row.get(1)?,
+                        priority: row.get(2)?,
+                        recipients: row.get::<_, i64>(3)? as usize,
+                        status: row.get(4)?,
+                        attempts: row.get(5)?,
+                        send_after: row.get(6)?,
+                        result: row.get(7)?,
+                        created_at: row.get(8)?,
+                        finished_at: row.get(9)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Takes the oldest emails that are due at `now`, counting an attempt
+    /// for each of them.
+    fn take_due(&self, now: i64, limit: usize) -> Result<Vec<QueuedEmail>, Error> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let due = tx
+            .prepare(
+                "SELECT id, key_id, request, attempts + 1 FROM queue
+                 WHERE status = ?1 AND send_after <= ?2
+                 ORDER BY send_after, id LIMIT ?3",
+            )?
+            .query_map(
+                params![QueueStatus::Queued.as_str(), now, limit as i64],
+                |row| {
+                    Ok(QueuedEmail {
+                        id: row.get(0)?,
+                        key_id: row.get(1)?,
+                        request: row.get(2)?,
+                        attempts: row.get(3)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for email in &due {
+            tx.execute(
+                "UPDATE queue SET attempts = attempts + 1 WHERE id = ?1",
+                params![email.id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(due)
+    }
+
+    fn postpone(&self, id: i64, send_after: i64) -> Result<(), Error> {
+        self.conn().execute(
+            "UPDATE queue SET send_after = ?2 WHERE id = ?1",
+            params![id, send_after],
+        )?;
+        Ok(())
+    }
+
+    fn finish(&self, id: i64, status: QueueStatus, result: &str) -> Result<(), Error> {
+        self.conn().execute(
+            "UPDATE queue SET status = ?2, result = ?3, finished_at = ?4 WHERE id = ?1",
+            params![id, status.as_str(), result, store::now()],
+        )?;
+        Ok(())
+    }
+}
+
+impl Client {
+    /// Puts a request in the queue, to be sent on behalf of whoever made it
+    /// once there is room in the quota, and not before `send_after` if set.
+    pub fn enqueue(
+        &self,
+        admission: &Admission,
+        mut request: QueuedRequest,
+        recipients: usize,
+        send_after: Option<i64>,
+    ) -> Result<SendOutcome, Error> {
+        if let QueuedRequest::Legacy(mail) = &mut request {
+            mail.key.clear();
+        }
+
+        let id = self.store.enqueue(
+            &admission.key_id(),
+            &request,
+            recipients,
+            send_after.unwrap_or_else(store::now),
+        )?;
+        info!("Queued email {} to {} recipients", id, recipients);
+        Ok(SendOutcome::Queued(id))
+    }
+
+    /// Sends whatever in the queue is due. Emails that still don't fit in
+    /// the quota are tried again after the next quota refresh, up to
+    /// [`MAX_ATTEMPTS`] times.
+    pub async fn process_queue(&self) -> Result<(), Error> {
+        for email in self.store.take_due(store::now(), POLL_BATCH)? {
+            let admission = Admission::Queued {
+                key_id: email.key_id,
+            };
+            let result = match serde_json::from_str::<QueuedRequest>(&email.request) {
+                Ok(QueuedRequest::Legacy(mail)) => self.deliver_legacy(*mail, admission).await,
+                Ok(QueuedRequest::Raw(mail)) => self.deliver_raw(mail, admission).await,
+                Err(e) => Err(Error::Store(format!("Invalid queued request: {}", e))),
+            };
+
+            match result {
+                Ok(SendOutcome::Single(message_id)) => {
+                    self.store
+                        .finish(email.id, QueueStatus::Sent, &message_id)?
+                }
+                Ok(SendOutcome::Batched(summary)) => {
+                    let summary = serde_json::to_string(&summary).unwrap_or_default();
+                    self.store.finish(email.id, QueueStatus::Sent, &summary)?
+                }
+                Ok(SendOutcome::Queued(_)) => {
+                    unreachable!("queued emails are never queued again")
+                }
+                // The key was put in the sandbox after the email was queued
+                Ok(SendOutcome::DryRun(_)) => self.store.finish(
+                    email.id,
+                    QueueStatus::Failed,
+                    "not sent, the key is in the sandbox",
+                )?,
+                Err(Error::SendQuotaExceeded(_, _)) if email.attempts >= MAX_ATTEMPTS => {
+                    warn!("Giving up on queued email {}", email.id);
+                    self.store.finish(
+                        email.id,
+                        QueueStatus::Failed,
+                        &format!("not sent after {} attempts", email.attempts),
+                    )?
+                }
+                Err(Error::SendQuotaExceeded(_, _)) => {
+                    let retry_after = self.config.quota.refresh_interval.max(1) as i64;
+                    self.store.postpone(email.id, store::now() + retry_after)?
+                }
+                Err(e) => {
+                    warn!("Failed to send queued email {}: {}", email.id, e.redacted());
+                    self.store
+                        .finish(email.id, QueueStatus::Failed, &e.to_string())?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What is in the queue, for admins.
+#[get("/queue")]
+async fn list_queue(
+    ses: web::Data<Client>,
+    filter: web::Query<QueueFilter>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ses.store.queue_entries(&filter)?))
+}
+
+/// Works through the queue for as long as the server runs.
+pub fn spawn_worker(client: web::Data<Client>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(POLL_INTERVAL).await;
+            if let Err(e) = client.process_queue().await {
+                warn!("Failed to process queue: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> QueuedRequest {
+        QueuedRequest::Legacy(Box::new(
+            serde_json::from_str(
+                r#"{
+                    "key": "",
+                    "from": "sender@datasektionen.se",
+                    "to": ["a@datasektionen.se", "b@datasektionen.se"],
+                    "subject": "Nyhetsbrev",
+                    "content": "Hej!",
+                    "priority": "bulk"
+                }"#,
+            )
+            .unwrap(),
+        ))
+    }
+
+    #[test]
+    fn round_trips_requests() {
+        let json = serde_json::to_string(&request()).unwrap();
+        let Ok(QueuedRequest::Legacy(mail)) = serde_json::from_str(&json) else {
+            panic!("Expected a legacy request");
+        };
+        assert_eq!(mail.subject, "Nyhetsbrev");
+        assert_eq!(mail.priority, Priority::Bulk);
+    }
+
+    #[test]
+    fn takes_due_emails_in_order() {
+        let store = Store::in_memory().unwrap();
+        let later = store.enqueue("key", &request(), 2, 200).unwrap();
+        let first = store.enqueue("key", &request(), 2, 100).unwrap();
+        assert_eq!(store.queue_depth().unwrap(), 2);
+
+        let due = store.take_due(150, POLL_BATCH).unwrap();
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), vec![first]);
+
+        store.postpone(first, 300).unwrap();
+        let due = store.take_due(250, POLL_BATCH).unwrap();
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), vec![later]);
+
+        store
+            .finish(later, QueueStatus::Sent, "message-id")
+            .unwrap();
+        let due = store.take_due(1000, POLL_BATCH).unwrap();
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), vec![first]);
+        assert_eq!(due[0].attempts, 2);
+
+        let filter = QueueFilter {
+            status: Some(QueueStatus::Queued),
+            ..Default::default()
+        };
+        let queued = store.queue_entries(&filter).unwrap();
+        assert_eq!(queued.iter().map(|e| e.id).collect::<Vec<_>>(), vec![first]);
+        assert_eq!(queued[0].attempts, 2);
+    }
+}
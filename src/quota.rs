@@ -0,0 +1,196 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web;
+use tracing::{info, warn};
+
+use crate::Client;
+use crate::error::Error;
+use crate::metrics::METRICS;
+
+/// How long to ask clients to wait when the account's 24 hour quota is used
+/// up. SES counts over a rolling window, so there is no exact answer.
+const QUOTA_RETRY_AFTER: u64 = 3600;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Seconds between reading the account's quota from SES.
+    pub refresh_interval: u64,
+    /// Share of the 24 hour quota that is kept for transactional mail.
+    pub reserve: f64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: 300,
+            reserve: 0.1,
+        }
+    }
+}
+
+/// The account's sending limits, as reported by SES' `GetAccount`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SendQuota {
+    /// Negative if the account has no 24 hour limit.
+    pub max_24_hour_send: f64,
+    pub max_send_rate: f64,
+    pub sent_last_24_hours: f64,
+}
+
+impl SendQuota {
+    /// Checks that sending to `recipients` more recipients leaves `reserve`
+    /// of the 24 hour quota unused.
+    fn check(&self, recipients: usize, reserve: f64) -> Result<(), Error> {
+        if self.max_24_hour_send < 0.0 {
+            return Ok(());
+        }
+
+        let cap = self.max_24_hour_send * (1.0 - reserve.clamp(0.0, 1.0));
+
+        if self.sent_last_24_hours + recipients as f64 > cap {
+            return Err(Error::SendQuotaExceeded(
+                format!(
+                    "{} of {} emails sent in the last 24 hours",
+                    self.sent_last_24_hours, self.max_24_hour_send
+                ),
+                QUOTA_RETRY_AFTER,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The last known quota, plus whatever we have sent since it was read.
+#[derive(Debug, Default)]
+pub struct SesQuota {
+    quota: RwLock<Option<SendQuota>>,
+}
+
+impl SesQuota {
+    pub fn get(&self) -> Option<SendQuota> {
+        *self.quota.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set(&self, quota: SendQuota) {
+        *self.quota.write().unwrap_or_else(|e| e.into_inner()) = Some(quota);
+    }
+
+    /// Counts emails to `recipients` recipients as sent until the next
+    /// refresh tells us the real number.
+    pub fn record(&self, recipients: usize) {
+        if let Some(quota) = self
+            .quota
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+        {
+            quota.sent_last_24_hours += recipients as f64;
+        }
+    }
+}
+
+impl Client {
+    /// Reads the account's quota from SES. On failure the previous quota is
+    /// kept.
+    pub async fn refresh_quota(&self) {
+        let timer = METRICS.time_ses("get_account");
+        let account = self.inner.get_account().send().await;
+        drop(timer);
+
+        let quota = match account {
+            Ok(account) => account.send_quota().map(|quota| SendQuota {
+                max_24_hour_send: quota.max24_hour_send(),
+                max_send_rate: quota.max_send_rate(),
+                sent_last_24_hours: quota.sent_last24_hours(),
+            }),
+            Err(e) => {
+                warn!("Failed to get SES send quota: {}", e);
+                return;
+            }
+        };
+
+        match quota {
+            Some(quota) => {
+                if self.quota.get().is_none() {
+                    info!("SES send quota: {:?}", quota);
+                }
+                self.quota.set(quota);
+            }
+            None => warn!("SES did not return a send quota"),
+        }
+    }
+
+    /// Checks that the account has room left for `recipients` more
+    /// recipients, besides the reserve for transactional mail. If the quota
+    /// hasn't been read yet, everything is let through and SES gets the final
+    /// say.
+    pub fn check_quota(&self, recipients: usize) -> Result<(), Error> {
+        let Some(quota) = self.quota.get() else {
+            return Ok(());
+        };
+
+        quota
+            .check(recipients, self.config.quota.reserve)
+            .inspect_err(|e| warn!("Rejecting send to {} recipients: {}", recipients, e))
+    }
+}
+
+/// Keeps the quota up to date for as long as the server runs.
+pub fn spawn_refresh(client: web::Data<Client>) {
+    let interval = Duration::from_secs(client.config.quota.refresh_interval.max(1));
+    actix_web::rt::spawn(async move {
+        loop {
+            client.refresh_quota().await;
+            actix_web::rt::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: SendQuota = SendQuota {
+        max_24_hour_send: 1000.0,
+        max_send_rate: 14.0,
+        sent_last_24_hours: 850.0,
+    };
+
+    #[test]
+    fn rejects_sends_over_the_cap() {
+        assert!(QUOTA.check(150, 0.0).is_ok());
+        assert!(matches!(
+            QUOTA.check(151, 0.0),
+            Err(Error::SendQuotaExceeded(_, _))
+        ));
+    }
+
+    #[test]
+    fn leaves_the_reserve() {
+        assert!(QUOTA.check(50, 0.1).is_ok());
+        assert!(QUOTA.check(51, 0.1).is_err());
+    }
+
+    #[test]
+    fn negative_max_is_unlimited() {
+        let quota = SendQuota {
+            max_24_hour_send: -1.0,
+            ..QUOTA
+        };
+        assert!(quota.check(1_000_000, 0.1).is_ok());
+    }
+
+    #[test]
+    fn records_sends_until_refresh() {
+        let quota = SesQuota::default();
+        quota.record(10);
+        assert_eq!(quota.get(), None);
+
+        quota.set(QUOTA);
+        quota.record(10);
+        assert_eq!(quota.get().unwrap().sent_last_24_hours, 860.0);
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use aws_sdk_sesv2::types::{Destination, EmailContent, RawMessage};
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::address::{self, Mailbox};
+use crate::audit::AuditEntry;
+use crate::delivery::{Admission, Admitted, DryRunMessage, Priority, SendOutcome};
+use crate::error::Error;
+use crate::hive::Principal;
+use crate::legacy::email::ListNameLegacy;
+use crate::logging::Redacted;
+use crate::metrics::METRICS;
+use crate::queue::QueuedRequest;
+use crate::{Client, tags, verify_sender_domain};
+
+fn encoding_default() -> String {
+    "base64".to_string()
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct RawEmailRequest {
+    /// The complete RFC 5322 message, headers included.
+    pub raw: String,
+    #[serde(default = "encoding_default")]
+    pub encoding: String,
+    /// Envelope recipients. If omitted, SES delivers to the addresses in the
+    /// message's `To`, `Cc` and `Bcc` headers.
+    pub destinations: Option<ListNameLegacy>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Extra SES message tags.
+    pub tags: Option<BTreeMap<String, String>>,
+    /// Checks the message, and returns it instead of sending it.
+    #[serde(rename = "dryRun", alias = "dry_run", default)]
+    pub dry_run: bool,
+}
+
+impl Debug for RawEmailRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawEmailRequest")
+            .field("raw", &format!("<{} bytes>", self.raw.len()))
+            .field("encoding", &self.encoding)
+            .field("destinations", &Redacted(&self.destinations))
+            .field("priority", &self.priority)
+            .field("tags", &self.tags)
+            .field("dry_run", &self.dry_run)
+            .finish()
+    }
+}
+
+impl RawEmailRequest {
+    pub fn decode(&self) -> Result<Vec<u8>, Error> {
+        match self.encoding.as_str() {
+            "base64" | "BASE64" | "Base64" => BASE64_STANDARD
+                .decode(&self.raw)
+                .map_err(|e| Error::RawMessage(format!("Failed to decode message: {}", e))),
+            "utf-8" | "utf8" | "UTF-8" | "UTF8" => Ok(self.raw.as_bytes().to_vec()),
+            _ => Err(Error::RawMessage(format!(
+                "Unsupported message encoding: {}",
+                self.encoding
+            ))),
+        }
+    }
+}
+
+/// Returns the unfolded value of every header called `name` in the header
+/// section of `message`.
+fn header_values(message: &[u8], name: &str) -> Result<Vec<String>, Error> {
+    let message = String::from_utf8_lossy(message);
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in message.split('\n') {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            let (_, value) = headers.last_mut().ok_or_else(|| {
+                Error::RawMessage("message starts with a continuation line".to_string())
+            })?;
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+
+        let (field, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::RawMessage(format!("malformed header line: {}", line)))?;
+        headers.push((field.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(headers
+        .into_iter()
+        .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+        .collect())
+}
+
+/// Who a raw message is from and to, as read from its headers and
+/// `destinations`.
+struct Envelope {
+    from: Vec<Mailbox>,
+    senders: Vec<Mailbox>,
+    destination: Option<Destination>,
+    addresses: Vec<String>,
+}
+
+impl Envelope {
+    /// Reads the envelope of `data`, describing it in `entry` as it goes, so
+    /// that the audit log shows what was checked and sent.
+    fn read(mail: &RawEmailRequest, data: &[u8], entry: &mut AuditEntry) -> Result<Self, Error> {
+        if let Some(subject) = header_values(data, "Subject")?.first() {
+            entry.subject = subject.clone();
+        }
+
+        let from = match header_values(data, "From")?.as_slice() {
+            [from] => address::parse_list(from)?,
+            [] => return Err(Error::RawMessage("missing From header".to_string())),
+            _ => return Err(Error::RawMessage("multiple From headers".to_string())),
+        };
+        let Some(first) = from.first() else {
+            return Err(Error::RawMessage("empty From header".to_string()));
+        };
+        entry.from = first.address();
+
+        let senders = header_values(data, "Sender")?
+            .iter()
+            .map(|sender| address::parse(sender))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let destination = mail
+            .destinations
+            .as_ref()
+            .map(|addrs| addrs.try_into())
+            .transpose()?
+            .map(|to| Destination::builder().set_to_addresses(Some(to)).build());
+
+        let addresses = match &destination {
+            Some(dest) => dest
+                .to_addresses()
+                .iter()
+                .map(|mailbox| address::addresses(mailbox))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat(),
+            None => ["To", "Cc", "Bcc"]
+                .iter()
+                .map(|name| header_values(data, name))
+                .collect::<Result<Vec<_>, _>>()?
+                .iter()
+                .flatten()
+                .map(|value| address::addresses(value))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat(),
+        };
+        entry.recipients = addresses.len();
+        if addresses.is_empty() {
+            return Err(Error::InvalidAddress(
+                "the message has no recipients".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            from,
+            senders,
+            destination,
+            addresses,
+        })
+    }
+}
+
+impl Client {
+    pub async fn send_raw_email(
+        &self,
+        mail: RawEmailRequest,
+        principal: Principal,
+    ) -> Result<SendOutcome, Error> {
+        let admission = Admission::Request { principal };
+        self.deliver_raw(mail, admission).await
+    }
+
+    /// Sends `mail`, recording the attempt in the audit log.
+    pub async fn deliver_raw(
+        &self,
+        mail: RawEmailRequest,
+        admission: Admission,
+    ) -> Result<SendOutcome, Error> {
+        let mut entry = AuditEntry::raw(&admission.key_id());
+        let result = self.dispatch_raw(mail, admission, &mut entry).await;
+        self.audit(entry, &result);
+        result
+    }
+
+    async fn dispatch_raw(
+        &self,
+        mail: RawEmailRequest,
+        admission: Admission,
+        entry: &mut AuditEntry,
+    ) -> Result<SendOutcome, Error> {
+        let mut data = mail.decode()?;
+        let Envelope {
+            from,
+            senders,
+            destination: mut dest,
+            mut addresses,
+        } = Envelope::read(&mail, &data, entry)?;
+
+        for mailbox in from.iter().chain(&senders) {
+            verify_sender_domain(mailbox)?;
+            admission.authorize_from(&mailbox.address()).await?;
+        }
+        let domain = verify_sender_domain(&from[0])?;
+        let tags = tags::message_tags(
+            &admission.key_id(),
+            "raw",
+            domain.as_str(),
+            mail.tags.as_ref(),
+        )?;
+
+        let policy = &self.config.recipient_policy;
+        if let Some(header) = policy.header(&addresses)? {
+            addresses = policy.addresses(&addresses);
+            // Everyone was dropped by the recipient policy
+            if addresses.is_empty() {
+                return SendOutcome::batched(Vec::new());
+            }
+            dest = Some(
+                Destination::builder()
+                    .set_to_addresses(Some(addresses.clone()))
+                    .build(),
+            );
+            data = [
+                format!("{}: {}\r\n", header.name(), header.value()).into_bytes(),
+                data,
+            ]
+            .concat();
+        }
+        let recipients = addresses.len();
+
+        if mail.dry_run
+            || self
+                .config
+                .sandbox
+                .is_dry_run(&admission.key_id(), &addresses)
+        {
+            return Ok(SendOutcome::DryRun(vec![DryRunMessage {
+                recipients: addresses,
+                mime: String::from_utf8_lossy(&data).into_owned(),
+            }]));
+        }
+
+        if self.admit(&admission, recipients, mail.priority).await? == Admitted::Later {
+            return self.enqueue(&admission, QueuedRequest::Raw(mail), recipients, None);
+        }
+        self.pace(recipients, mail.priority).await;
+
+        let raw = RawMessage::builder()
+            .data(data.into())
+            .build()
+            .map_err(|e| Error::RawMessage(format!("Failed to build raw message: {}", e)))?;
+
+        let timer = METRICS.time_ses("send_email");
+        let resp = self
+            .inner
+            .send_email()
+            .set_destination(dest)
+            .set_configuration_set_name(self.configuration_set(mail.priority, &domain))
+            .set_email_tags(Some(tags))
+            .content(EmailContent::builder().raw(raw).build())
+            .send()
+            .await
+            .map_err(|e| Error::EmailSend(format!("Email failed to send: {}", e)));
+        drop(timer);
+
+        METRICS.record_send(domain.as_str(), "raw", recipients, &resp);
+        let resp = resp?;
+
+        Ok(SendOutcome::Single(
+            resp.message_id().map(|s| s.to_string()).unwrap_or_default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "From: \"Doe, John\" <john@datasektionen.se>\r\n\
+        To: list@datasektionen.se\r\n\
+        Subject: A long subject that has been\r\n \
+        folded onto two lines\r\n\
+        \r\n\
+        From: body@evil.com\r\n";
+
+    #[test]
+    fn unfolds_headers() {
+        let subject = header_values(MESSAGE.as_bytes(), "subject").unwrap();
+        assert_eq!(
+            subject,
+            vec!["A long subject that has been folded onto two lines"]
+        );
+    }
+
+    #[test]
+    fn ignores_body() {
+        let from = header_values(MESSAGE.as_bytes(), "From").unwrap();
+        assert_eq!(from, vec!["\"Doe, John\" <john@datasektionen.se>"]);
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        assert!(header_values(b"From john@datasektionen.se\r\n\r\n", "From").is_err());
+    }
+
+    #[test]
+    fn describes_what_is_sent() {
+        let mail = RawEmailRequest {
+            raw: "From: Ture <ture@datasektionen.se>\r\n\
+                To: a@datasektionen.se, b@datasektionen.se\r\n\
+                Cc: \"C, D\" <c@datasektionen.se>\r\n\
+                Subject: =?UTF-8?Q?R=C3=A4kna?=\r\n\
+                \r\n\
+                Hej\r\n"
+                .to_string(),
+            encoding: "utf-8".to_string(),
+            destinations: None,
+            priority: Default::default(),
+            tags: None,
+            dry_run: false,
+        };
+        let mut entry = AuditEntry::raw("key");
+        let envelope = Envelope::read(&mail, &mail.decode().unwrap(), &mut entry).unwrap();
+        assert_eq!(envelope.addresses.len(), 3);
+        assert_eq!(entry.from, "ture@datasektionen.se");
+        assert_eq!(entry.recipients, 3);
+        assert_eq!(entry.subject, "=?UTF-8?Q?R=C3=A4kna?=");
+
+        // Up to where the message stopped making sense
+        let mail = RawEmailRequest {
+            raw: "Subject: Hej\r\nFrom: a@datasektionen.se\r\nFrom: b@datasektionen.se\r\n\r\n"
+                .to_string(),
+            ..mail
+        };
+        let mut entry = AuditEntry::raw("key");
+        assert!(Envelope::read(&mail, &mail.decode().unwrap(), &mut entry).is_err());
+        assert_eq!(entry.subject, "Hej");
+        assert_eq!(entry.from, "");
+    }
+
+    #[test]
+    fn needs_recipients() {
+        for raw in [
+            "From: a@datasektionen.se\r\n\r\nHej\r\n",
+            "From: a@datasektionen.se\r\nTo: undisclosed-recipients:;\r\nCc:\r\n\r\nHej\r\n",
+        ] {
+            let mail: RawEmailRequest = serde_json::from_value(serde_json::json!({
+                "raw": raw,
+                "encoding": "utf-8",
+            }))
+            .unwrap();
+            let mut entry = AuditEntry::raw("key");
+            assert!(matches!(
+                Envelope::read(&mail, &mail.decode().unwrap(), &mut entry),
+                Err(Error::InvalidAddress(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn decodes_base64() {
+        let json = r#"{
+            "raw": "RnJvbTogYUBkYXRhc2VrdGlvbmVuLnNlDQoNCmhp"
+        }"#;
+        let req: RawEmailRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.decode().unwrap(), b"From: a@datasektionen.se\r\n\r\nhi");
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+/// Keys that only make dry runs, for developers trying spam out against
+/// production.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// By key id (see [`crate::hive::key_id`]).
+    pub keys: HashMap<String, SandboxKey>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SandboxKey {
+    /// Test addresses the key may still really send to.
+    pub deliver_to: Vec<String>,
+}
+
+impl SandboxConfig {
+    /// Whether an email by `key_id` to `recipients` may only be a dry run.
+    /// Sandbox keys only send for real when every recipient is one of their
+    /// test addresses.
+    pub fn is_dry_run(&self, key_id: &str, recipients: &[String]) -> bool {
+        let Some(key) = self.keys.get(key_id) else {
+            return false;
+        };
+        recipients.is_empty()
+            || !recipients.iter().all(|recipient| {
+                key.deliver_to
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(recipient))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    #[test]
+    fn sandbox_keys_only_deliver_to_test_addresses() {
+        let sandbox = Config::parse(
+            r#"
+            [sandbox.keys.0123456789abcdef]
+            deliver_to = ["test@datasektionen.se"]
+            "#,
+        )
+        .unwrap()
+        .sandbox;
+        let recipients = |addresses: &[&str]| {
+            addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(!sandbox.is_dry_run("fedcba9876543210", &recipients(&["a@kth.se"])));
+        assert!(!sandbox.is_dry_run("0123456789abcdef", &recipients(&["Test@datasektionen.se"])));
+        assert!(sandbox.is_dry_run(
+            "0123456789abcdef",
+            &recipients(&["test@datasektionen.se", "a@kth.se"])
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use aws_sdk_sesv2::types::MessageHeader;
+
+use crate::address;
+use crate::delivery::Batch;
+use crate::error::Error;
+
+/// Keeps the recipients an email had before [`RecipientPolicy`] changed them.
+pub const ORIGINAL_RECIPIENTS: &str = "X-Spam-Original-Recipients";
+
+/// SES turns away header values much longer than this.
+const MAX_HEADER_LENGTH: usize = 800;
+
+/// What happens to the recipients of every email, for deployments that must
+/// never mail real people, like staging.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum RecipientPolicy {
+    /// Everyone gets their email.
+    #[default]
+    Deliver,
+    /// Every email goes to `to` instead.
+    Redirect { to: String },
+    /// Recipients outside `domains` are dropped.
+    Allowlist { domains: Vec<String> },
+}
+
+impl RecipientPolicy {
+    /// Whether every address in `mailbox` is in an allowed domain. Anything
+    /// that doesn't parse isn't.
+    fn allows(&self, mailbox: &str) -> bool {
+        let Self::Allowlist { domains } = self else {
+            return true;
+        };
+        address::parse_list(mailbox).is_ok_and(|mailboxes| {
+            mailboxes.iter().all(|mailbox| {
+                domains
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(mailbox.domain()))
+            })
+        })
+    }
+
+    /// The recipients of an email sent in batches. Batches left without
+    /// anyone are dropped.
+    pub fn batches(&self, batches: Vec<Batch>) -> Vec<Batch> {
+        match self {
+            Self::Deliver => batches,
+            Self::Redirect { to } if batches.iter().any(|batch| batch.len() > 0) => {
+                vec![Batch {
+                    to: vec![to.clone()],
+                    ..Default::default()
+                }]
+            }
+            Self::Redirect { .. } => Vec::new(),
+            Self::Allowlist { .. } => batches
+                .into_iter()
+                .map(|batch| {
+                    let keep = |mailboxes: Vec<String>| {
+                        mailboxes
+                            .into_iter()
+                            .filter(|mailbox| self.allows(mailbox))
+                            .collect()
+                    };
+                    Batch {
+                        to: keep(batch.to),
+                        cc: keep(batch.cc),
+                        bcc: keep(batch.bcc),
+                    }
+                })
+                .filter(|batch| batch.len() > 0)
+                .collect(),
+        }
+    }
+
+    /// Where an email to just `mailbox` goes, if anywhere.
+    pub fn mailbox(&self, mailbox: &str) -> Option<String> {
+        match self {
+            Self::Redirect { to } => Some(to.clone()),
+            _ if self.allows(mailbox) => Some(mailbox.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The envelope recipients of a raw email to `addresses`.
+    pub fn addresses(&self, addresses: &[String]) -> Vec<String> {
+        addresses
+            .iter()
+            .filter_map(|address| self.mailbox(address))
+            .fold(Vec::new(), |mut kept, address| {
+                if !kept.contains(&address) {
+                    kept.push(address);
+                }
+                kept
+            })
+    }
+
+    /// The [`ORIGINAL_RECIPIENTS`] header for an email to `addresses`, if the
+    /// policy changes recipients at all. Long lists are cut short.
+    pub fn header(&self, addresses: &[String]) -> Result<Option<MessageHeader>, Error> {
+        if *self == Self::Deliver {
+            return Ok(None);
+        }
+
+        let mut value = String::new();
+        for (i, address) in addresses.iter().enumerate() {
+            if value.len() + address.len() > MAX_HEADER_LENGTH {
+                value.push_str(&format!(" (and {} more)", addresses.len() - i));
+                break;
+            }
+            if i > 0 {
+                value.push_str(", ");
+            }
+            value.push_str(address);
+        }
+
+        MessageHeader::builder()
+            .name(ORIGINAL_RECIPIENTS)
+            .value(value)
+            .build()
+            .map(Some)
+            .map_err(|e| Error::InvalidHeader(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn policy(config: &str) -> RecipientPolicy {
+        Config::parse(config).unwrap().recipient_policy
+    }
+
+    fn batch(to: &[&str], bcc: &[&str]) -> Batch {
+        Batch {
+            to: to.iter().map(|s| s.to_string()).collect(),
+            cc: Vec::new(),
+            bcc: bcc.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn redirects_everything_to_the_catch_all() {
+        let policy = policy(
+            r#"
+            [recipient_policy]
+            mode = "redirect"
+            to = "staging@datasektionen.se"
+            "#,
+        );
+
+        let batches = policy.batches(vec![batch(&["a@kth.se"], &["b@gmail.com"])]);
+        assert_eq!(batches, vec![batch(&["staging@datasektionen.se"], &[])]);
+        assert_eq!(
+            policy.mailbox("Ture <ture@kth.se>").as_deref(),
+            Some("staging@datasektionen.se")
+        );
+        assert_eq!(
+            policy
+                .header(&["a@kth.se".to_string(), "b@gmail.com".to_string()])
+                .unwrap()
+                .unwrap()
+                .value(),
+            "a@kth.se, b@gmail.com"
+        );
+
+        let members = (0..100)
+            .map(|i| format!("member{}@datasektionen.se", i))
+            .collect::<Vec<_>>();
+        let header = policy.header(&members).unwrap().unwrap();
+        assert!(header.value().len() < 900);
+        assert!(header.value().ends_with("(and 70 more)"));
+    }
+
+    #[test]
+    fn drops_recipients_outside_allowed_domains() {
+        let policy = policy(
+            r#"
+            [recipient_policy]
+            mode = "allowlist"
+            domains = ["datasektionen.se"]
+            "#,
+        );
+
+        let batches = policy.batches(vec![
+            batch(&["\"Doe, John\" <john@Datasektionen.se>", "a@kth.se"], &[]),
+            batch(&[], &["b@gmail.com"]),
+        ]);
+        assert_eq!(
+            batches,
+            vec![batch(&["\"Doe, John\" <john@Datasektionen.se>"], &[])]
+        );
+        assert_eq!(policy.mailbox("a@kth.se"), None);
+        assert_eq!(
+            policy.addresses(&["a@kth.se".to_string(), "d-sys@datasektionen.se".to_string()]),
+            vec!["d-sys@datasektionen.se".to_string()]
+        );
+    }
+
+    #[test]
+    fn delivers_by_default() {
+        let policy = RecipientPolicy::default();
+        let batches = vec![batch(&["a@kth.se"], &["b@gmail.com"])];
+        assert_eq!(policy.batches(batches.clone()), batches);
+        assert_eq!(policy.mailbox("a@kth.se").as_deref(), Some("a@kth.se"));
+        assert!(policy.header(&["a@kth.se".to_string()]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::Connection;
+
+use crate::error::Error;
+
+/// Tables are only ever added, never changed, so the whole schema is applied
+/// on every startup.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS unsubscribes (
+    key_id TEXT NOT NULL,
+    list TEXT NOT NULL,
+    address TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (key_id, list, address)
+);
+
+CREATE TABLE IF NOT EXISTS lists (
+    owner TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (owner, id)
+);
+
+CREATE TABLE IF NOT EXISTS list_members (
+    owner TEXT NOT NULL,
+    list_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    name TEXT,
+    vars TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (owner, list_id, address),
+    FOREIGN KEY (owner, list_id) REFERENCES lists (owner, id)
+);
+
+CREATE TABLE IF NOT EXISTS usage (
+    key_id TEXT NOT NULL,
+    period TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    recipients INTEGER NOT NULL,
+    PRIMARY KEY (key_id, period)
+);
+
+CREATE TABLE IF NOT EXISTS queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key_id TEXT NOT NULL,
+    priority TEXT NOT NULL,
+    request TEXT NOT NULL,
+    recipients INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    send_after INTEGER NOT NULL,
+    result TEXT,
+    created_at INTEGER NOT NULL,
+    finished_at INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    key_id TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    recipients INTEGER NOT NULL,
+    subject TEXT NOT NULL,
+    template TEXT NOT NULL,
+    attachments TEXT NOT NULL,
+    result TEXT NOT NULL,
+    message_ids TEXT NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS audit_key_id ON audit (key_id, id);
+
+CREATE TRIGGER IF NOT EXISTS audit_no_update BEFORE UPDATE ON audit
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_no_delete BEFORE DELETE ON audit
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+
+CREATE TABLE IF NOT EXISTS template_replacements (
+    name TEXT PRIMARY KEY NOT NULL,
+    source TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS limit_overrides (
+    key_id TEXT PRIMARY KEY NOT NULL,
+    per_minute INTEGER,
+    daily INTEGER,
+    monthly INTEGER,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    key_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    request_hash TEXT NOT NULL,
+    status INTEGER,
+    content_type TEXT,
+    body BLOB,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (key_id, key)
+);
+";
+
+/// Persistent state, kept in a local SQLite database.
+#[derive(Debug)]
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::init(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic while holding the lock can't leave SQLite in a bad state,
+        // so there is no reason to give up on the connection.
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Seconds since the Unix epoch, which is how timestamps are stored.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
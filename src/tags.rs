@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+
+use aws_sdk_sesv2::types::MessageTag;
+
+use crate::error::Error;
+
+/// Tag names starting with this are set by spam itself.
+const RESERVED_PREFIX: &str = "spam-";
+
+/// How many tags a caller may add on top of spam's own.
+const MAX_CUSTOM_TAGS: usize = 10;
+
+/// SES limits tag names and values to this many characters.
+const MAX_TAG_LENGTH: usize = 256;
+
+fn is_tag_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn tag(name: &str, value: &str) -> Result<MessageTag, Error> {
+    MessageTag::builder()
+        .name(name)
+        .value(value)
+        .build()
+        .map_err(|e| Error::InvalidTag(format!("{}: {}", name, e)))
+}
+
+/// Makes `value` safe to use as a tag value, since SES doesn't allow e.g.
+/// the dots in a domain.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .take(MAX_TAG_LENGTH)
+        .map(|c| if is_tag_char(c) { c } else { '_' })
+        .collect()
+}
+
+fn validate(name: &str, value: &str) -> Result<(), Error> {
+    for part in [name, value] {
+        if part.is_empty() || part.len() > MAX_TAG_LENGTH || !part.chars().all(is_tag_char) {
+            return Err(Error::InvalidTag(format!(
+                "{}: names and values must be 1 to {} letters, digits, '_' or '-'",
+                name, MAX_TAG_LENGTH
+            )));
+        }
+    }
+
+    if name.to_ascii_lowercase().starts_with(RESERVED_PREFIX) {
+        return Err(Error::InvalidTag(format!(
+            "{}: names starting with {} are reserved",
+            name, RESERVED_PREFIX
+        )));
+    }
+
+    Ok(())
+}
+
+/// Tags for an email sent with the key `key_id` from `domain`, so that SES
+/// events can be told apart by who sent them. Tags given by the caller are
+/// added after spam's own.
+pub fn message_tags(
+    key_id: &str,
+    template: &str,
+    domain: &str,
+    custom: Option<&BTreeMap<String, String>>,
+) -> Result<Vec<MessageTag>, Error> {
+    let custom = custom.into_iter().flatten().collect::<Vec<_>>();
+    if custom.len() > MAX_CUSTOM_TAGS {
+        return Err(Error::InvalidTag(format!(
+            "at most {} tags are allowed",
+            MAX_CUSTOM_TAGS
+        )));
+    }
+
+    let mut tags = vec![
+        tag("spam-key", &sanitize(key_id))?,
+        tag("spam-template", &sanitize(template))?,
+        tag("spam-domain", &sanitize(domain))?,
+    ];
+    for (name, value) in custom {
+        validate(name, value)?;
+        tags.push(tag(name, value)?);
+    }
+
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(tags: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tags_key_template_and_domain() {
+        let tags = message_tags("0123456789abcdef", "default", "datasektionen.se", None).unwrap();
+        let tags = tags
+            .iter()
+            .map(|tag| (tag.name(), tag.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            vec![
+                ("spam-key", "0123456789abcdef"),
+                ("spam-template", "default"),
+                ("spam-domain", "datasektionen_se"),
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_custom_tags() {
+        let custom = custom(&[("campaign", "reccen-2026")]);
+        let tags = message_tags("key", "none", "ddagen.se", Some(&custom)).unwrap();
+        assert_eq!(tags.len(), 4);
+        assert_eq!(tags[3].name(), "campaign");
+    }
+
+    #[test]
+    fn rejects_invalid_custom_tags() {
+        for tags in [
+            custom(&[("spam-key", "forged")]),
+            custom(&[("SPAM-domain", "forged")]),
+            custom(&[("campaign", "two words")]),
+            custom(&[("", "empty")]),
+            custom(&[("åäö", "x")]),
+        ] {
+            assert!(message_tags("key", "none", "ddagen.se", Some(&tags)).is_err());
+        }
+    }
+
+    #[test]
+    fn limits_custom_tags() {
+        let tags = (0..=MAX_CUSTOM_TAGS)
+            .map(|i| (format!("tag{}", i), "x".to_string()))
+            .collect();
+        assert!(message_tags("key", "none", "ddagen.se", Some(&tags)).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::{Span, error};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::Registry;
+
+use crate::error::Error;
+
+/// Passes spans on to OpenTelemetry.
+pub type TracerLayer = OpenTelemetryLayer<Registry, SdkTracer>;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TracingConfig {
+    /// Where to send spans over OTLP/HTTP, e.g.
+    /// `http://localhost:4318/v1/traces`. Nothing is exported if unset.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "spam".to_string(),
+        }
+    }
+}
+
+/// Exports spans for as long as it is kept, flushing whatever is left when
+/// shut down.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Err(e) = self.provider.shutdown() {
+            error!("Failed to flush spans: {}", e);
+        }
+    }
+}
+
+/// Sets up exporting spans to the configured endpoint, if there is one.
+pub fn init(config: &TracingConfig) -> Result<Option<(Telemetry, TracerLayer)>, Error> {
+    let Some(endpoint) = &config.endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| Error::Config(format!("Invalid tracing endpoint: {}", e)))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("spam"));
+
+    Ok(Some((Telemetry { provider }, layer)))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Continues the trace given in the request's `traceparent` header, if any.
+pub fn set_parent(span: &Span, request: &ServiceRequest) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    let _ = span.set_parent(context);
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use actix_web::{HttpResponse, delete, get, put, web};
+use handlebars::{Handlebars, RenderError};
+use rusqlite::params;
+
+use crate::Client;
+use crate::error::Error;
+use crate::store::{self, Store};
+
+#[derive(Debug)]
+struct Entry {
+    file: String,
+    file_source: String,
+    /// Set by an admin, replacing the file until it is reset.
+    replacement: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub file: String,
+    pub replaced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// The Handlebars templates emails and pages are rendered with. They are
+/// loaded from files, and can be replaced while running. Replacements are
+/// kept in the store, so they survive restarts.
+#[derive(Debug, Default)]
+pub struct Templates {
+    registry: RwLock<Handlebars<'static>>,
+    entries: RwLock<BTreeMap<String, Entry>>,
+}
+
+fn compile(registry: &mut Handlebars<'static>, name: &str, source: &str) -> Result<(), Error> {
+    registry
+        .register_template_string(name, source)
+        .map_err(|e| Error::InvalidTemplate(format!("{}: {}", name, e)))
+}
+
+impl Templates {
+    fn registry(&self) -> RwLockReadGuard<'_, Handlebars<'static>> {
+        self.registry.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers `name` from the contents of `file`.
+    pub fn register_file(&self, name: &str, file: &str, source: String) -> Result<(), Error> {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let mut registry = self.registry.write().unwrap_or_else(|e| e.into_inner());
+
+        compile(&mut registry, name, &source)
+            .map_err(|e| Error::TemplateLoad(format!("Failed to register {}: {}", file, e)))?;
+        entries.insert(
+            name.to_string(),
+            Entry {
+                file: file.to_string(),
+                file_source: source,
+                replacement: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Applies the replacements kept in `store` to the templates loaded so
+    /// far.
+    pub fn load_replacements(&self, store: &Store) -> Result<(), Error> {
+        for (name, source) in store.template_replacements()? {
+            if let Err(e) = self.apply(&name, Some(source)) {
+                tracing::warn!("Ignoring replacement of template {}: {}", name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, name: &str, replacement: Option<String>) -> Result<TemplateInfo, Error> {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let entry = entries
+            .get_mut(name)
+            .ok_or_else(|| Error::NotFound(format!("template {}", name)))?;
+
+        let mut registry = self.registry.write().unwrap_or_else(|e| e.into_inner());
+        compile(
+            &mut registry,
+            name,
+            replacement.as_deref().unwrap_or(&entry.file_source),
+        )?;
+        entry.replacement = replacement;
+
+        Ok(info(name, entry, true))
+    }
+
+    /// Replaces the template `name` with `source`, which has to compile.
+    pub fn replace(
+        &self,
+        store: &Store,
+        name: &str,
+        source: String,
+    ) -> Result<TemplateInfo, Error> {
+        let info = self.apply(name, Some(source.clone()))?;
+        store.replace_template(name, &source)?;
+        Ok(info)
+    }
+
+    /// Goes back to the template in its file.
+    pub fn reset(&self, store: &Store, name: &str) -> Result<TemplateInfo, Error> {
+        let info = self.apply(name, None)?;
+        store.reset_template(name)?;
+        Ok(info)
+    }
+
+    pub fn render<T: serde::Serialize>(&self, name: &str, data: &T) -> Result<String, RenderError> {
+        self.registry().render(name, data)
+    }
+
+    pub fn has_template(&self, name: &str) -> bool {
+        self.registry().has_template(name)
+    }
+
+    pub fn list(&self) -> Vec<TemplateInfo> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries
+            .iter()
+            .map(|(name, entry)| info(name, entry, false))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<TemplateInfo, Error> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(name)
+            .map(|entry| info(name, entry, true))
+            .ok_or_else(|| Error::NotFound(format!("template {}", name)))
+    }
+}
+
+fn info(name: &str, entry: &Entry, with_source: bool) -> TemplateInfo {
+    TemplateInfo {
+        name: name.to_string(),
+        file: entry.file.clone(),
+        replaced: entry.replacement.is_some(),
+        source: with_source.then(|| {
+            entry
+                .replacement
+                .clone()
+                .unwrap_or_else(|| entry.file_source.clone())
+        }),
+    }
+}
+
+impl Store {
+    fn template_replacements(&self) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name, source FROM template_replacements")?;
+        let replacements = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(replacements)
+    }
+
+    fn replace_template(&self, name: &str, source: &str) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT INTO template_replacements (name, source, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (name) DO UPDATE SET source = ?2, updated_at = ?3",
+            params![name, source, store::now()],
+        )?;
+        Ok(())
+    }
+
+    fn reset_template(&self, name: &str) -> Result<(), Error> {
+        self.conn().execute(
+            "DELETE FROM template_replacements WHERE name = ?1",
+            params![name],
+        )?;
+        Ok(())
+    }
+}
+
+#[get("/templates")]
+async fn list_templates(ses: web::Data<Client>) -> HttpResponse {
+    HttpResponse::Ok().json(ses.templates.list())
+}
+
+#[get("/templates/{name:.*}")]
+async fn get_template(
+    ses: web::Data<Client>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ses.templates.get(&name)?))
+}
+
+/// Replaces a template with the Handlebars source in the body.
+#[put("/templates/{name:.*}")]
+async fn put_template(
+    ses: web::Data<Client>,
+    name: web::Path<String>,
+    source: String,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ses.templates.replace(&ses.store, &name, source)?))
+}
+
+/// Goes back to the template's file.
+#[delete("/templates/{name:.*}")]
+async fn delete_template(
+    ses: web::Data<Client>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ses.templates.reset(&ses.store, &name)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_and_resets_templates() {
+        let store = Store::in_memory().unwrap();
+        let templates = Templates::default();
+        templates
+            .register_file("greeting", "greeting.hbs", "Hej {{name}}!".to_string())
+            .unwrap();
+
+        let data = serde_json::json!({ "name": "Ture" });
+        templates
+            .replace(&store, "greeting", "Hallå {{name}}!".to_string())
+            .unwrap();
+        assert_eq!(templates.render("greeting", &data).unwrap(), "Hallå Ture!");
+        assert!(templates.list()[0].replaced);
+
+        // Replacements outlive a restart
+        let restarted = Templates::default();
+        restarted
+            .register_file("greeting", "greeting.hbs", "Hej {{name}}!".to_string())
+            .unwrap();
+        restarted.load_replacements(&store).unwrap();
+        assert_eq!(restarted.render("greeting", &data).unwrap(), "Hallå Ture!");
+
+        restarted.reset(&store, "greeting").unwrap();
+        assert_eq!(restarted.render("greeting", &data).unwrap(), "Hej Ture!");
+        assert!(store.template_replacements().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_broken_and_unknown_templates() {
+        let store = Store::in_memory().unwrap();
+        let templates = Templates::default();
+        templates
+            .register_file("greeting", "greeting.hbs", "Hej!".to_string())
+            .unwrap();
+
+        assert!(matches!(
+            templates.replace(&store, "greeting", "{{#if}}".to_string()),
+            Err(Error::InvalidTemplate(_))
+        ));
+        assert_eq!(templates.render("greeting", &()).unwrap(), "Hej!");
+        assert!(matches!(
+            templates.replace(&store, "other", "Hej!".to_string()),
+            Err(Error::NotFound(_))
+        ));
+    }
+}
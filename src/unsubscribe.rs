@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::env;
+
+use actix_web::{HttpResponse, get, post, web};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use rusqlite::params;
+use sha2::Sha256;
+
+use crate::Client;
+use crate::error::Error;
+use crate::store::{Store, now};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Identifies one recipient on one list of one key. Signed with `APP_SECRET`,
+/// it is what ends up in the unsubscribe links of every list email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsubscribeToken {
+    /// The key the list belongs to (see [`crate::hive::Principal::id`]), as
+    /// lists of different keys may have the same name.
+    pub key_id: String,
+    pub list: String,
+    pub address: String,
+}
+
+fn app_secret() -> Result<String, Error> {
+    env::var("APP_SECRET").map_err(|_| Error::EnvVarMissing("APP_SECRET".to_string()))
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+impl UnsubscribeToken {
+    pub fn new(key_id: &str, list: &str, address: &str) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            list: list.to_string(),
+            address: address.trim().to_lowercase(),
+        }
+    }
+
+    pub fn sign(&self, secret: &[u8]) -> String {
+        let payload = serde_json::to_string(&(&self.key_id, &self.list, &self.address))
+            .expect("strings serialize");
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(payload);
+        let mut mac = mac(secret);
+        mac.update(payload.as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify(token: &str, secret: &[u8]) -> Result<Self, Error> {
+        let (payload, signature) = token.split_once('.').ok_or(Error::InvalidToken)?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::InvalidToken)?;
+
+        let mut mac = mac(secret);
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| Error::InvalidToken)?;
+
+        let payload = BASE64_URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| Error::InvalidToken)?;
+        let (key_id, list, address): (String, String, String) =
+            serde_json::from_slice(&payload).map_err(|_| Error::InvalidToken)?;
+
+        Ok(Self::new(&key_id, &list, &address))
+    }
+}
+
+/// The hosted unsubscribe link for `address` on `list` of `key_id`.
+pub fn unsubscribe_url(key_id: &str, list: &str, address: &str) -> Result<String, Error> {
+    let public_url =
+        env::var("PUBLIC_URL").map_err(|_| Error::EnvVarMissing("PUBLIC_URL".to_string()))?;
+    let token = UnsubscribeToken::new(key_id, list, address).sign(app_secret()?.as_bytes());
+    Ok(format!(
+        "{}/unsubscribe/{}",
+        public_url.trim_end_matches('/'),
+        token
+    ))
+}
+
+impl Store {
+    pub fn unsubscribe(&self, token: &UnsubscribeToken) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO unsubscribes (key_id, list, address, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![token.key_id, token.list, token.address, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the (lowercased) addresses that have unsubscribed from `list`
+    /// of `key_id`.
+    pub fn unsubscribed(&self, key_id: &str, list: &str) -> Result<HashSet<String>, Error> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT address FROM unsubscribes WHERE key_id = ?1 AND list = ?2")?;
+        let addresses = stmt
+            .query_map(params![key_id, list], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        Ok(addresses)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct UnsubscribePage<'a> {
+    token: &'a str,
+    list: &'a str,
+    address: &'a str,
+}
+
+fn render_page(
+    ses: &Client,
+    page: &str,
+    token: &str,
+    unsub: &UnsubscribeToken,
+) -> Result<HttpResponse, Error> {
+    let data = UnsubscribePage {
+        token,
+        list: &unsub.list,
+        address: &unsub.address,
+    };
+    let html = ses.templates.render(page, &data)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+#[get("/unsubscribe/{token}")]
+async fn unsubscribe_page(
+    ses: web::Data<Client>,
+    token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let unsub = UnsubscribeToken::verify(&token, app_secret()?.as_bytes())?;
+    render_page(&ses, "unsubscribe/confirm", &token, &unsub)
+}
+
+/// Handles both the confirmation form and one-click unsubscribe (RFC 8058)
+/// requests from mail clients.
+#[post("/unsubscribe/{token}")]
+async fn unsubscribe(
+    ses: web::Data<Client>,
+    token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let unsub = UnsubscribeToken::verify(&token, app_secret()?.as_bytes())?;
+    ses.store.unsubscribe(&unsub)?;
+    render_page(&ses, "unsubscribe/done", &token, &unsub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"insert-app-secret";
+
+    #[test]
+    fn roundtrip() {
+        let token = UnsubscribeToken::new("0123456789abcdef", "nyheter", "Ture@Datasektionen.se");
+        let signed = token.sign(SECRET);
+        let verified = UnsubscribeToken::verify(&signed, SECRET).unwrap();
+        assert_eq!(verified.key_id, "0123456789abcdef");
+        assert_eq!(verified.list, "nyheter");
+        assert_eq!(verified.address, "ture@datasektionen.se");
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let signed = UnsubscribeToken::new("0123456789abcdef", "nyheter", "ture@datasektionen.se")
+            .sign(SECRET);
+        assert!(UnsubscribeToken::verify(&signed, b"another-secret").is_err());
+    }
+
+    #[test]
+    fn tampered_payload() {
+        let signed = UnsubscribeToken::new("0123456789abcdef", "nyheter", "ture@datasektionen.se")
+            .sign(SECRET);
+        let (_, signature) = signed.split_once('.').unwrap();
+        let other = BASE64_URL_SAFE_NO_PAD
+            .encode(r#"["0123456789abcdef","nyheter","other@datasektionen.se"]"#);
+        let forged = format!("{}.{}", other, signature);
+        assert!(UnsubscribeToken::verify(&forged, SECRET).is_err());
+    }
+
+    #[test]
+    fn stores_unsubscribes_per_key_and_list() {
+        let store = Store::in_memory().unwrap();
+        let token = UnsubscribeToken::new("0123456789abcdef", "nyheter", "ture@datasektionen.se");
+        store.unsubscribe(&token).unwrap();
+        store.unsubscribe(&token).unwrap();
+
+        let unsubscribed = store.unsubscribed("0123456789abcdef", "nyheter").unwrap();
+        assert!(unsubscribed.contains("ture@datasektionen.se"));
+        assert!(
+            store
+                .unsubscribed("0123456789abcdef", "other")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            store
+                .unsubscribed("fedcba9876543210", "nyheter")
+                .unwrap()
+                .is_empty()
+        );
+    }
+}